@@ -0,0 +1,467 @@
+use anyhow::{anyhow, bail, Result};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::process::ExitStatus;
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::servers::Server;
+
+/// The failures worth telling apart in what the aws CLI and session-manager-plugin write to
+/// stderr, since they call for very different fixes.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Problem {
+    /// Temporary credentials ran out, e.g. from an assumed role or an MFA session.
+    ExpiredToken,
+    /// The SSO login behind the profile needs doing again.
+    SsoExpired,
+    AccessDenied,
+    /// The instance's SSM agent isn't connected right now.
+    TargetNotConnected,
+    InvalidInstanceId,
+}
+
+impl Problem {
+    /// Picks the problem out of a line of output, if it's one we know.
+    pub fn classify(line: &str) -> Option<Self> {
+        // SSO failures go first, as they tend to mention the expired token they tripped over.
+        const PATTERNS: &[(&str, Problem)] = &[
+            ("Token has expired and refresh failed", Problem::SsoExpired),
+            (
+                "SSO session associated with this profile",
+                Problem::SsoExpired,
+            ),
+            ("Error loading SSO Token", Problem::SsoExpired),
+            ("ExpiredToken", Problem::ExpiredToken),
+            (
+                "security token included in the request is expired",
+                Problem::ExpiredToken,
+            ),
+            ("AccessDenied", Problem::AccessDenied),
+            ("is not authorized to perform", Problem::AccessDenied),
+            ("TargetNotConnected", Problem::TargetNotConnected),
+            ("InvalidInstanceId", Problem::InvalidInstanceId),
+        ];
+        PATTERNS
+            .iter()
+            .find(|(pattern, _)| line.contains(pattern))
+            .map(|(_, problem)| problem.clone())
+    }
+
+    /// Whether logging in again is the fix.
+    pub fn needs_login(&self) -> bool {
+        matches!(self, Problem::ExpiredToken | Problem::SsoExpired)
+    }
+
+    /// Whether trying again later could work without anyone changing anything.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Problem::TargetNotConnected)
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::ExpiredToken => write!(f, "Credentials expired"),
+            Problem::SsoExpired => write!(f, "SSO session expired"),
+            Problem::AccessDenied => write!(f, "Access denied"),
+            Problem::TargetNotConnected => write!(f, "Target not connected"),
+            Problem::InvalidInstanceId => write!(f, "Invalid instance ID"),
+        }
+    }
+}
+
+/// The aws CLI as a server wants it run: its binary, profile, region and environment. Expects
+/// the server to have had its defaults applied.
+pub fn command(server: &Server, args: &[&str]) -> Command {
+    let mut command = match &server.aws_binary {
+        Some(program) => program.command(),
+        None => Command::new("aws"),
+    };
+    command.args(args);
+    if let Some(region) = &server.region {
+        command.args(["--region", region]);
+    }
+    command.env("AWS_PROFILE", &server.env);
+    command.envs(&server.env_vars);
+    command
+}
+
+/// `command`, but as the assumed role when there is one.
+pub fn command_as(server: &Server, credentials: Option<&Credentials>, args: &[&str]) -> Command {
+    let mut command = command(server, args);
+    if let Some(credentials) = credentials {
+        credentials.apply(&mut command);
+    }
+    command
+}
+
+/// Runs `aws sso login` on the user's terminal, which it needs for the device code prompt.
+pub async fn sso_login(server: &Server) -> io::Result<ExitStatus> {
+    command(server, &["sso", "login", "--profile", &server.env])
+        .status()
+        .await
+}
+
+/// Who a profile's credentials belong to, according to STS.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Identity {
+    #[serde(rename = "Account")]
+    pub account: String,
+    #[serde(rename = "Arn")]
+    pub arn: String,
+}
+
+/// Runs an aws command for its JSON output. On failure the error is the last thing it said,
+/// which is where the CLI puts the reason.
+async fn json<T: DeserializeOwned>(mut command: Command) -> Result<T> {
+    let output = command.output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.lines().rev().find(|line| !line.trim().is_empty());
+        return Err(anyhow!(reason
+            .unwrap_or("aws exited without saying why")
+            .to_string()));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// Runs `aws sts get-caller-identity`, which fails quickly and plainly where a session would
+/// fail slowly and confusingly. With a role's credentials, that's the role's account.
+pub async fn caller_identity(
+    server: &Server,
+    credentials: Option<&Credentials>,
+) -> Result<Identity> {
+    json(command_as(
+        server,
+        credentials,
+        &["sts", "get-caller-identity", "--output", "json"],
+    ))
+    .await
+}
+
+/// How long assumed role credentials are asked to last. An hour is the most that every role
+/// allows, including ones reached by chaining.
+pub const ROLE_DURATION: Duration = Duration::from_secs(3600);
+
+/// Temporary credentials from `sts assume-role`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: String,
+}
+
+impl Credentials {
+    /// Puts the credentials in a command's environment, where they take precedence over the
+    /// profile's own. The profile stays set so its region still applies.
+    pub fn apply(&self, command: &mut Command) {
+        command.env("AWS_ACCESS_KEY_ID", &self.access_key_id);
+        command.env("AWS_SECRET_ACCESS_KEY", &self.secret_access_key);
+        command.env("AWS_SESSION_TOKEN", &self.session_token);
+    }
+}
+
+/// Assumes the server's `roleArn` using its profile. Expects the server to have had its
+/// defaults applied.
+pub async fn assume_role(server: &Server) -> Result<Credentials> {
+    #[derive(Deserialize)]
+    struct Output {
+        #[serde(rename = "Credentials")]
+        credentials: Credentials,
+    }
+
+    let role = server
+        .role_arn
+        .as_deref()
+        .ok_or_else(|| anyhow!("{} has no role to assume", server.name))?;
+    let session_name = server.role_session_name.as_deref().unwrap_or("secure-cord");
+    let duration = ROLE_DURATION.as_secs().to_string();
+    let mut args = vec![
+        "sts",
+        "assume-role",
+        "--role-arn",
+        role,
+        "--role-session-name",
+        session_name,
+        "--duration-seconds",
+        &duration,
+        "--output",
+        "json",
+    ];
+    if let Some(external_id) = &server.external_id {
+        args.extend(["--external-id", external_id]);
+    }
+    let output: Output = json(command(server, &args)).await?;
+    Ok(output.credentials)
+}
+
+/// An instance the profile's SSM inventory knows about.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ManagedInstance {
+    pub instance_id: String,
+    /// `Online`, `ConnectionLost` or `Inactive`.
+    pub ping_status: String,
+    #[serde(default)]
+    pub platform_name: Option<String>,
+    #[serde(default)]
+    pub computer_name: Option<String>,
+    #[serde(rename = "IPAddress", default)]
+    pub ip_address: Option<String>,
+    /// The EC2 Name tag, which SSM doesn't know about.
+    #[serde(skip)]
+    pub name: Option<String>,
+}
+
+/// Runs `aws ssm describe-instance-information`, filling in names from EC2 tags.
+pub async fn managed_instances(server: &Server) -> Result<Vec<ManagedInstance>> {
+    #[derive(Deserialize)]
+    struct Output {
+        #[serde(rename = "InstanceInformationList")]
+        instances: Vec<ManagedInstance>,
+    }
+    #[derive(Deserialize)]
+    struct Tags {
+        #[serde(rename = "Tags")]
+        tags: Vec<Tag>,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Tag {
+        resource_id: String,
+        value: String,
+    }
+
+    let args = ["ssm", "describe-instance-information", "--output", "json"];
+    let mut instances = json::<Output>(command(server, &args)).await?.instances;
+
+    // Names are a nicety, and hybrid instances don't have EC2 tags anyway, so a profile that
+    // can't read them still gets its list.
+    let args = [
+        "ec2",
+        "describe-tags",
+        "--filters",
+        "Name=resource-type,Values=instance",
+        "Name=key,Values=Name",
+        "--output",
+        "json",
+    ];
+    if let Ok(tags) = json::<Tags>(command(server, &args)).await {
+        let names: HashMap<_, _> = tags
+            .tags
+            .into_iter()
+            .map(|tag| (tag.resource_id, tag.value))
+            .collect();
+        for instance in &mut instances {
+            instance.name = names.get(&instance.instance_id).cloned();
+        }
+    }
+    Ok(instances)
+}
+
+/// Running instances with the tag that SSM has online, newest first, since an autoscaling
+/// group's latest replacement is the one most likely to stick around.
+pub async fn resolve_tag(
+    server: &Server,
+    (key, value): (&str, &str),
+    credentials: Option<&Credentials>,
+) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Reservations {
+        reservations: Vec<Reservation>,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Reservation {
+        instances: Vec<Instance>,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Instance {
+        instance_id: String,
+        #[serde(default)]
+        launch_time: String,
+    }
+    #[derive(Deserialize)]
+    struct Information {
+        #[serde(rename = "InstanceInformationList")]
+        instances: Vec<ManagedInstance>,
+    }
+
+    let tag = format!("Name=tag:{},Values={}", key, value);
+    let args = [
+        "ec2",
+        "describe-instances",
+        "--filters",
+        &tag,
+        "Name=instance-state-name,Values=running",
+        "--output",
+        "json",
+    ];
+    let output: Reservations = json(command_as(server, credentials, &args)).await?;
+    let mut running: Vec<_> = output
+        .reservations
+        .into_iter()
+        .flat_map(|reservation| reservation.instances)
+        .collect();
+    if running.is_empty() {
+        bail!("No running instance is tagged {}={}", key, value);
+    }
+    // Timestamps in the same format sort the same as the times they stand for.
+    running.sort_by(|a, b| b.launch_time.cmp(&a.launch_time));
+
+    let ids: Vec<_> = running.iter().map(|i| i.instance_id.as_str()).collect();
+    let filter = format!("Key=InstanceIds,Values={}", ids.join(","));
+    let count = ids.len();
+    let args = [
+        "ssm",
+        "describe-instance-information",
+        "--filters",
+        &filter,
+        "--output",
+        "json",
+    ];
+    let output: Information = json(command_as(server, credentials, &args)).await?;
+    let online: HashSet<_> = output
+        .instances
+        .into_iter()
+        .filter(|i| i.ping_status == "Online")
+        .map(|i| i.instance_id)
+        .collect();
+    let candidates: Vec<_> = running
+        .into_iter()
+        .map(|i| i.instance_id)
+        .filter(|id| online.contains(id))
+        .collect();
+    if candidates.is_empty() {
+        bail!(
+            "None of the {} running instance(s) tagged {}={} are online in SSM",
+            count,
+            key,
+            value
+        );
+    }
+    Ok(candidates)
+}
+
+/// Ends an SSM session on AWS's side, which killing the local plugin doesn't reliably do.
+pub async fn terminate_session(
+    server: &Server,
+    session_id: &str,
+    credentials: Option<&Credentials>,
+) -> Result<()> {
+    let args = [
+        "ssm",
+        "terminate-session",
+        "--session-id",
+        session_id,
+        "--output",
+        "json",
+    ];
+    json::<IgnoredAny>(command_as(server, credentials, &args)).await?;
+    Ok(())
+}
+
+/// The credentials to run things as for a server: its role's if it has one, otherwise nothing
+/// beyond the profile.
+pub async fn credentials_for(server: &Server) -> Result<Option<Credentials>> {
+    match server.role_arn {
+        Some(_) => Ok(Some(assume_role(server).await?)),
+        None => Ok(None),
+    }
+}
+
+/// A session SSM has open, whoever opened it.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RemoteSession {
+    pub session_id: String,
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Usually a timestamp string, but the CLI can be configured to give epoch seconds.
+    #[serde(default)]
+    pub start_date: Option<serde_json::Value>,
+    #[serde(default)]
+    pub document_name: Option<String>,
+}
+
+impl RemoteSession {
+    pub fn started(&self) -> String {
+        match &self.start_date {
+            Some(serde_json::Value::String(date)) => date.clone(),
+            Some(other) => other.to_string(),
+            None => String::new(),
+        }
+    }
+}
+
+/// Runs `aws ssm describe-sessions --state Active` for one target.
+pub async fn active_sessions(
+    server: &Server,
+    target: &str,
+    credentials: Option<&Credentials>,
+) -> Result<Vec<RemoteSession>> {
+    #[derive(Deserialize)]
+    struct Output {
+        #[serde(rename = "Sessions")]
+        sessions: Vec<RemoteSession>,
+    }
+
+    let filter = format!("key=Target,value={}", target);
+    let args = [
+        "ssm",
+        "describe-sessions",
+        "--state",
+        "Active",
+        "--filters",
+        &filter,
+        "--output",
+        "json",
+    ];
+    let output: Output = json(command_as(server, credentials, &args)).await?;
+    Ok(output.sessions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_cli_errors() {
+        let cases = [
+            (
+                "An error occurred (ExpiredTokenException) when calling the StartSession operation: The security token included in the request is expired",
+                Some(Problem::ExpiredToken),
+            ),
+            (
+                "Error when retrieving token from sso: Token has expired and refresh failed",
+                Some(Problem::SsoExpired),
+            ),
+            (
+                "The SSO session associated with this profile has expired or is otherwise invalid. To refresh this SSO session run aws sso login with the corresponding profile.",
+                Some(Problem::SsoExpired),
+            ),
+            (
+                "An error occurred (AccessDeniedException) when calling the StartSession operation: User: arn:aws:sts::123456789012:assumed-role/dev/alice is not authorized to perform: ssm:StartSession on resource: arn:aws:ec2:eu-west-1:123456789012:instance/i-0123456789abcdef0",
+                Some(Problem::AccessDenied),
+            ),
+            (
+                "An error occurred (TargetNotConnected) when calling the StartSession operation: i-0123456789abcdef0 is not connected.",
+                Some(Problem::TargetNotConnected),
+            ),
+            (
+                "An error occurred (InvalidInstanceId) when calling the DescribeInstanceInformation operation: ",
+                Some(Problem::InvalidInstanceId),
+            ),
+            ("Waiting for connections...", None),
+        ];
+        for (line, problem) in cases {
+            assert_eq!(Problem::classify(line), problem, "{}", line);
+        }
+    }
+}
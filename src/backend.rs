@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::aws;
+use crate::servers::Server;
+
+/// Something that can hold a port forward open for as long as the process it spawns lives.
+///
+/// The session actor owns everything else: stdio capture, health checks, restarts. A backend only
+/// has to describe the command, and forward to `127.0.0.1:host_port` on the local end.
+pub trait Backend {
+    fn command(&self, server: &Server) -> Command;
+}
+
+/// Which backend a server uses, as written in jobs.json. Entries without one are SSM.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum BackendConfig {
+    #[default]
+    Ssm,
+    Ssh(Ssh),
+    Kubectl(Kubectl),
+    Command(Template),
+}
+
+impl BackendConfig {
+    pub fn is_ssm(&self) -> bool {
+        matches!(self, BackendConfig::Ssm)
+    }
+
+    pub fn as_backend(&self) -> &dyn Backend {
+        match self {
+            BackendConfig::Ssm => &Ssm,
+            BackendConfig::Ssh(ssh) => ssh,
+            BackendConfig::Kubectl(kubectl) => kubectl,
+            BackendConfig::Command(template) => template,
+        }
+    }
+}
+
+/// `aws ssm start-session`, with `identifier` as the instance and `env` as the AWS profile. Picks the
+/// remote host document when the server forwards past the instance, and passes `extraArgs` on.
+pub struct Ssm;
+
+impl Backend for Ssm {
+    fn command(&self, server: &Server) -> Command {
+        let (document, parameters) = match &server.remote_host {
+            Some(host) => (
+                "AWS-StartPortForwardingSessionToRemoteHost",
+                format!(
+                    "host={},portNumber={},localPortNumber={}",
+                    host, server.dest_port, server.host_port
+                ),
+            ),
+            None => (
+                "AWS-StartPortForwardingSession",
+                format!(
+                    "portNumber={},localPortNumber={}",
+                    server.dest_port, server.host_port
+                ),
+            ),
+        };
+        let mut command = aws::command(
+            server,
+            &[
+                "ssm",
+                "start-session",
+                "--target",
+                &server.identifier,
+                "--document-name",
+                document,
+                "--parameters",
+                &parameters,
+            ],
+        );
+        command.args(&server.extra_args);
+        command
+    }
+}
+
+/// `ssh -L`, with `identifier` as the destination (anything ssh accepts, including config aliases).
+/// Forwards to `remote_host` as resolved by the destination, if set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Ssh {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(
+        rename = "identityFile",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub identity_file: Option<String>,
+}
+
+impl Backend for Ssh {
+    fn command(&self, server: &Server) -> Command {
+        let mut command = Command::new("ssh");
+        command.args([
+            "-N",
+            "-o",
+            "ExitOnForwardFailure=yes",
+            "-o",
+            "BatchMode=yes",
+            "-L",
+            &format!(
+                "127.0.0.1:{}:{}:{}",
+                server.host_port,
+                server.remote_host.as_deref().unwrap_or("localhost"),
+                server.dest_port
+            ),
+        ]);
+        if let Some(port) = self.port {
+            command.args(["-p", &port.to_string()]);
+        }
+        if let Some(identity_file) = &self.identity_file {
+            command.args(["-i", identity_file]);
+        }
+        command.arg(&server.identifier);
+        command
+    }
+}
+
+/// `kubectl port-forward`, with `identifier` as the resource, e.g. `svc/postgres`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Kubectl {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+}
+
+impl Backend for Kubectl {
+    fn command(&self, server: &Server) -> Command {
+        let mut command = Command::new("kubectl");
+        command.args([
+            "port-forward",
+            "--address",
+            "127.0.0.1",
+            &server.identifier,
+            &format!("{}:{}", server.host_port, server.dest_port),
+        ]);
+        if let Some(namespace) = &self.namespace {
+            command.args(["--namespace", namespace]);
+        }
+        if let Some(context) = &self.context {
+            command.args(["--context", context]);
+        }
+        command
+    }
+}
+
+/// Anything else. `{target}`, `{env}`, `{name}`, `{hostPort}` and `{destPort}` are substituted
+/// into the program and each argument.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Template {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl Template {
+    fn render(template: &str, server: &Server) -> String {
+        template
+            .replace("{target}", &server.identifier)
+            .replace("{env}", &server.env)
+            .replace("{name}", &server.name)
+            .replace("{hostPort}", &server.host_port.to_string())
+            .replace("{destPort}", &server.dest_port.to_string())
+    }
+}
+
+impl Backend for Template {
+    fn command(&self, server: &Server) -> Command {
+        let mut command = Command::new(Self::render(&self.program, server));
+        command.args(self.args.iter().map(|arg| Self::render(arg, server)));
+        command
+    }
+}
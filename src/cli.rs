@@ -0,0 +1,444 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::aws::Problem;
+use crate::control::{Client, Entry, Request};
+use crate::process::{self, Foreign};
+use crate::servers::{self, Server};
+use crate::ssm::{self, Phase, Session, SessionState};
+use crate::Command;
+use crate::{doctor, orphans};
+
+/// The servers scripts can see: the daemon's if one is running, otherwise whatever jobs.json
+/// says, all stopped as far as we know.
+async fn entries(client: Option<&Client>, connections_file: &Path) -> Result<Vec<Entry>> {
+    if let Some(client) = client {
+        return client.list().await;
+    }
+    Ok(servers::load(connections_file)
+        .await?
+        .servers
+        .into_iter()
+        .zip(1..)
+        .map(|(server, id)| Entry {
+            id,
+            server,
+            state: SessionState::default(),
+        })
+        .collect())
+}
+
+/// Picks out what each query names, by nickname or instance id. Names needn't be unique, so a
+/// query can match several, but it has to match something.
+fn select<T>(items: Vec<T>, queries: &[String], server: impl Fn(&T) -> &Server) -> Result<Vec<T>> {
+    if let Some(missing) = queries
+        .iter()
+        .find(|q| !items.iter().any(|item| matches(server(item), q)))
+    {
+        bail!("No server is called {}", missing);
+    }
+    Ok(items
+        .into_iter()
+        .filter(|item| queries.iter().any(|q| matches(server(item), q)))
+        .collect())
+}
+
+/// Like `select`, for commands that need exactly one tunnel.
+fn select_one<T>(items: Vec<T>, query: &str, server: impl Fn(&T) -> &Server) -> Result<T> {
+    let mut selected = select(items, &[query.to_string()], server)?;
+    if selected.len() > 1 {
+        bail!(
+            "More than one server goes by {}, so pick one by something only it has",
+            query
+        );
+    }
+    Ok(selected.remove(0))
+}
+
+fn matches(server: &Server, query: &str) -> bool {
+    server.name == query || server.identifier == query
+}
+
+/// The port a tunnel is reachable on, if that's known yet.
+fn port(server: &Server, state: &SessionState) -> Option<usize> {
+    state
+        .local_port
+        .or(Some(server.host_port).filter(|port| *port != 0))
+}
+
+fn print_table(rows: Vec<[String; 5]>) {
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in rows {
+        let line: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// What `status --json` prints per tunnel. Flat, so it's easy to pick apart with jq.
+#[derive(Serialize)]
+struct Status {
+    name: String,
+    #[serde(rename = "instanceId")]
+    identifier: String,
+    /// The instance a tag selector resolved to.
+    target: Option<String>,
+    #[serde(rename = "sessionId")]
+    session_id: Option<String>,
+    connections: usize,
+    env: String,
+    port: Option<usize>,
+    phase: String,
+    ready: bool,
+    retries: u32,
+    #[serde(rename = "lastError")]
+    last_error: Option<String>,
+    problem: Option<Problem>,
+}
+
+async fn list(client: Option<&Client>, connections_file: &Path) -> Result<()> {
+    let mut rows = vec![[
+        "NAME".to_string(),
+        "INSTANCE".to_string(),
+        "ENV".to_string(),
+        "PORT".to_string(),
+        "DEST".to_string(),
+    ]];
+    for Entry { server, .. } in entries(client, connections_file).await? {
+        rows.push([
+            server.name,
+            server.identifier,
+            server.env,
+            match server.host_port {
+                0 => "auto".to_string(),
+                port => port.to_string(),
+            },
+            match server.remote_host {
+                Some(host) => format!("{}:{}", host, server.dest_port),
+                None => server.dest_port.to_string(),
+            },
+        ]);
+    }
+    print_table(rows);
+    Ok(())
+}
+
+async fn status(client: Option<&Client>, connections_file: &Path, json: bool) -> Result<()> {
+    if client.is_none() {
+        eprintln!("No daemon is running, so this is only what's configured.");
+    }
+    let entries = entries(client, connections_file).await?;
+
+    if json {
+        let statuses: Vec<_> = entries
+            .into_iter()
+            .map(|Entry { server, state, .. }| Status {
+                port: port(&server, &state),
+                phase: state.phase.to_string(),
+                ready: state.phase == Phase::Ready,
+                retries: state.retries,
+                last_error: state.last_error,
+                problem: state.problem,
+                target: state.target,
+                session_id: state.session_id,
+                connections: state.connections,
+                name: server.name,
+                identifier: server.identifier,
+                env: server.env,
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+        return Ok(());
+    }
+
+    let mut rows = vec![[
+        "NAME".to_string(),
+        "INSTANCE".to_string(),
+        "PORT".to_string(),
+        "STATUS".to_string(),
+        "LAST ERROR".to_string(),
+    ]];
+    for Entry { server, state, .. } in entries {
+        rows.push([
+            server.name.clone(),
+            state.target.clone().unwrap_or(server.identifier.clone()),
+            port(&server, &state).map_or("-".to_string(), |port| port.to_string()),
+            state.label(),
+            state.last_error.unwrap_or_default(),
+        ]);
+    }
+    print_table(rows);
+    Ok(())
+}
+
+async fn up(client: Option<&Client>, connections_file: &Path, queries: &[String]) -> Result<()> {
+    if let Some(client) = client {
+        for entry in select(client.list().await?, queries, |e| &e.server)? {
+            client.request(&Request::Start { id: entry.id }).await?;
+            eprintln!("Starting {}", entry.server.name);
+        }
+        return Ok(());
+    }
+
+    // Nothing outlives us without a daemon, so hold the tunnels open until told otherwise.
+    let config = servers::load(connections_file).await?;
+    let servers = select(config.servers, queries, |s| s)?;
+    // Caught from here on, so an early interrupt still stops what's been started.
+    let shutdown = process::shutdown_signal();
+    eprintln!(
+        "No daemon is running, so tunnels stay up until this is interrupted. \
+         Run `secure-cord daemon` to keep them in the background."
+    );
+    let sessions: Vec<_> = servers
+        .into_iter()
+        .map(|server| {
+            (
+                Session::new(server.clone(), config.defaults.clone()),
+                server,
+            )
+        })
+        .collect();
+    for (session, _) in &sessions {
+        session.start().await;
+    }
+
+    let watchers = sessions.iter().map(|(session, server)| {
+        let mut state = session.watch();
+        let name = server.name.clone();
+        async move {
+            // Every captured line counts as a change, so only say something when there's news.
+            let mut last = String::new();
+            while state.changed().await.is_ok() {
+                let current = state.borrow_and_update().clone();
+                let line = match (&current.phase, current.local_port, &current.last_error) {
+                    (Phase::Ready, Some(port), _) => format!("Ready on 127.0.0.1:{}", port),
+                    (phase, _, Some(error)) if !phase.is_active() => {
+                        format!("{} ({})", current.label(), error)
+                    }
+                    _ => current.label(),
+                };
+                if line != last {
+                    eprintln!("{}: {}", name, line);
+                    last = line;
+                }
+            }
+        }
+    });
+    tokio::select! {
+        _ = futures::future::join_all(watchers) => {}
+        _ = shutdown => {}
+    }
+
+    process::stop_all(sessions.into_iter().map(|(session, _)| session)).await;
+    Ok(())
+}
+
+async fn down(client: Option<&Client>, queries: &[String]) -> Result<()> {
+    let Some(client) = client else {
+        bail!(
+            "No daemon is running, so there's nothing to bring down. \
+             Tunnels brought up in the foreground stop when interrupted."
+        );
+    };
+    for entry in select(client.list().await?, queries, |e| &e.server)? {
+        client.request(&Request::Stop { id: entry.id }).await?;
+        eprintln!("Stopping {}", entry.server.name);
+    }
+    Ok(())
+}
+
+/// Resolves with the local port once the session is ready, or fails if it gives up first.
+/// `starting` says a start has only just been asked for, so the current state predates it.
+async fn ready(
+    mut state: watch::Receiver<SessionState>,
+    timeout: Duration,
+    starting: bool,
+) -> Result<usize> {
+    let wait = async {
+        // What it was up to before being started doesn't count as giving up.
+        let mut first = starting;
+        loop {
+            {
+                let current = state.borrow_and_update();
+                match (&current.phase, current.local_port) {
+                    (Phase::Ready, Some(port)) => return Ok(port),
+                    (phase, _) if phase.is_active() || first => {}
+                    _ => match &current.last_error {
+                        Some(error) => bail!("{} ({})", current.label(), error),
+                        None => bail!("{}", current.label()),
+                    },
+                }
+            }
+            first = false;
+            state
+                .changed()
+                .await
+                .map_err(|_| anyhow!("The session went away"))?;
+        }
+    };
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| anyhow!("Not ready after {}s", timeout.as_secs()))?
+}
+
+/// What a shell would report for the process, so wrappers can't tell we were in the way.
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(1)
+}
+
+/// Runs the user's command against a ready tunnel.
+async fn run_command(command: &[String], port: usize) -> Result<i32> {
+    let render = |arg: &String| arg.replace("{port}", &port.to_string());
+    // Listening before the child exists, so there's no moment an interrupt could kill us.
+    let terminated = process::termination_signal();
+    let mut child = tokio::process::Command::new(render(&command[0]))
+        .args(command[1..].iter().map(render))
+        .env("SECURE_CORD_PORT", port.to_string())
+        .spawn()
+        .with_context(|| format!("Couldn't run {}", command[0]))?;
+    let status = tokio::select! {
+        status = child.wait() => status?,
+        // Aimed only at us, so it needs passing on.
+        _ = terminated => {
+            if let Some(pid) = child.id() {
+                let _ = Foreign { pid, group: false }.terminate();
+            }
+            child.wait().await?
+        }
+    };
+    Ok(exit_code(status))
+}
+
+async fn exec(
+    client: Option<&Client>,
+    connections_file: &Path,
+    query: &str,
+    timeout: Duration,
+    command: &[String],
+) -> Result<()> {
+    // The tunnel runs in its own process group, out of the terminal's reach, so an interrupt while
+    // waiting has to be caught for it to be cleaned up.
+    let shutdown = process::shutdown_signal();
+    // A local session goes when we do. The daemon's is only stopped again if we started it.
+    let mut local = None;
+    let mut started = None;
+    let state = match client {
+        Some(client) => {
+            let entry = select_one(client.list().await?, query, |e| &e.server)?;
+            let state = client.watch(entry.id).await?;
+            if !entry.state.phase.is_active() {
+                client.request(&Request::Start { id: entry.id }).await?;
+                started = Some(entry.id);
+            }
+            state
+        }
+        None => {
+            let config = servers::load(connections_file).await?;
+            let server = select_one(config.servers, query, |s| s)?;
+            let session = Session::new(server, config.defaults);
+            let state = session.watch();
+            session.start().await;
+            local = Some(session);
+            state
+        }
+    };
+
+    let starting = local.is_some() || started.is_some();
+    let result = tokio::select! {
+        port = ready(state, timeout, starting) => match port {
+            Ok(port) => run_command(command, port).await,
+            Err(err) => Err(err.context(format!("{} didn't come up", query))),
+        },
+        _ = shutdown => Err(anyhow!("Interrupted while waiting for {}", query)),
+    };
+
+    if let Some(session) = local {
+        session.shutdown().await;
+    }
+    if let (Some(client), Some(id)) = (client, started) {
+        let _ = client.request(&Request::Stop { id }).await;
+    }
+    std::process::exit(result?);
+}
+
+async fn wait(
+    client: Option<&Client>,
+    connections_file: &Path,
+    query: &str,
+    timeout: Duration,
+) -> Result<()> {
+    if let Some(client) = client {
+        let entry = select_one(client.list().await?, query, |e| &e.server)?;
+        let port = ready(client.watch(entry.id).await?, timeout, false)
+            .await
+            .with_context(|| format!("{} isn't ready", query))?;
+        eprintln!("{} is ready on 127.0.0.1:{}", entry.server.name, port);
+        return Ok(());
+    }
+
+    // Whoever is running it isn't listening to us, so all there is to go on is the port.
+    let server = select_one(servers::load(connections_file).await?.servers, query, |s| s)?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let port = match server.host_port {
+            0 => orphans::recorded(&server.name).map(|record| record.host_port),
+            port => Some(port),
+        };
+        if let Some(port) = port {
+            if ssm::probe(port, Duration::from_secs(1)).await {
+                eprintln!("{} is ready on 127.0.0.1:{}", server.name, port);
+                return Ok(());
+            }
+        }
+        if tokio::time::Instant::now() >= deadline {
+            bail!(
+                "{} isn't accepting connections after {}s",
+                query,
+                timeout.as_secs()
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
+
+/// Runs one of the non-interactive commands, through the daemon when there is one.
+pub async fn run(command: Command, connections_file: &Path) -> Result<()> {
+    let client = Client::connect(connections_file).await;
+    let client = client.as_ref();
+    match command {
+        Command::List => list(client, connections_file).await,
+        Command::Status { json } => status(client, connections_file, json).await,
+        Command::Up { servers } => up(client, connections_file, &servers).await,
+        Command::Down { servers } => down(client, &servers).await,
+        Command::Exec {
+            server,
+            timeout,
+            command,
+        } => {
+            let timeout = Duration::from_secs(timeout);
+            exec(client, connections_file, &server, timeout, &command).await
+        }
+        Command::Wait { server, timeout } => {
+            let timeout = Duration::from_secs(timeout);
+            wait(client, connections_file, &server, timeout).await
+        }
+        Command::Doctor => doctor::run(connections_file).await,
+        Command::Daemon => unreachable!("The daemon isn't a client command"),
+    }
+}
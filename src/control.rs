@@ -0,0 +1,282 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, Lines};
+use tokio::sync::watch;
+
+use crate::servers::Server;
+use crate::ssm::{Session, SessionState};
+use crate::Uhh;
+
+/// Sessions are addressed by an id the daemon hands out, since names needn't be unique and
+/// indexes shift as other clients add and remove servers.
+pub type SessionId = u64;
+
+/// One line of JSON from a client. Every request gets exactly one response, except `Subscribe`,
+/// which turns the connection into a stream of `Event`s.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    List,
+    Status {
+        id: SessionId,
+    },
+    Start {
+        id: SessionId,
+    },
+    Stop {
+        id: SessionId,
+    },
+    Logs {
+        id: SessionId,
+    },
+    Subscribe,
+    Add {
+        server: Server,
+    },
+    Update {
+        id: SessionId,
+        server: Server,
+    },
+    Remove {
+        id: SessionId,
+    },
+    /// Write the daemon's servers back to its connections file.
+    Save,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    Added {
+        id: SessionId,
+    },
+    Entries {
+        entries: Vec<Entry>,
+    },
+    Entry {
+        entry: Box<Entry>,
+    },
+    Logs {
+        stdout: Vec<String>,
+        stderr: Vec<String>,
+    },
+    Event {
+        id: SessionId,
+        state: SessionState,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Entry {
+    pub id: SessionId,
+    pub server: Server,
+    pub state: SessionState,
+}
+
+/// Where the daemon for a connections file listens. Each file gets its own, so a UI or command
+/// pointed at one file never quietly drives (and saves over) another's daemon.
+pub fn socket_path(connections_file: &Path) -> PathBuf {
+    let file = std::fs::canonicalize(connections_file)
+        .or_else(|_| std::path::absolute(connections_file))
+        .unwrap_or_else(|_| connections_file.to_path_buf());
+    // Hashed, as socket paths are short. Client and daemon are the same binary, so they agree.
+    let mut hasher = DefaultHasher::new();
+    file.hash(&mut hasher);
+    crate::state_dir().join(format!("daemon-{:016x}.sock", hasher.finish()))
+}
+
+type Reader = Box<dyn AsyncBufRead + Send + Unpin>;
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Writes a value as a single line of JSON.
+pub async fn send_line<T: Serialize>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    value: &T,
+) -> Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// A way of talking to a running daemon. Each request uses its own connection, so this is cheap
+/// to clone and share between tasks.
+#[derive(Clone, Debug)]
+pub struct Client {
+    path: PathBuf,
+}
+
+impl Client {
+    /// Returns a client if a daemon for the connections file is answering.
+    pub async fn connect(connections_file: &Path) -> Option<Self> {
+        let client = Self {
+            path: socket_path(connections_file),
+        };
+        client.open().await.ok().map(|_| client)
+    }
+
+    #[cfg(unix)]
+    async fn open(&self) -> io::Result<(Reader, Writer)> {
+        let stream = tokio::net::UnixStream::connect(&self.path).await?;
+        let (read, write) = stream.into_split();
+        Ok((Box::new(tokio::io::BufReader::new(read)), Box::new(write)))
+    }
+
+    #[cfg(not(unix))]
+    async fn open(&self) -> io::Result<(Reader, Writer)> {
+        let _ = &self.path;
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    pub async fn request(&self, request: &Request) -> Result<Response> {
+        let (reader, mut writer) = self.open().await?;
+        send_line(&mut writer, request).await?;
+        let line = reader
+            .lines()
+            .next_line()
+            .await?
+            .ok_or_else(|| anyhow!("The daemon hung up without answering"))?;
+        match serde_json::from_str(&line)? {
+            Response::Error { message } => bail!(message),
+            response => Ok(response),
+        }
+    }
+
+    pub async fn list(&self) -> Result<Vec<Entry>> {
+        match self.request(&Request::List).await? {
+            Response::Entries { entries } => Ok(entries),
+            other => bail!("Unexpected response to list: {:?}", other),
+        }
+    }
+
+    pub async fn subscribe(&self) -> Result<Subscription> {
+        let (reader, mut writer) = self.open().await?;
+        send_line(&mut writer, &Request::Subscribe).await?;
+        Ok(Subscription {
+            lines: reader.lines(),
+            _writer: writer,
+        })
+    }
+
+    /// Follows a single session, for commands that only care about one tunnel. The sender goes
+    /// away with the daemon.
+    pub async fn watch(&self, id: SessionId) -> Result<watch::Receiver<SessionState>> {
+        let mut subscription = self.subscribe().await?;
+        let initial = loop {
+            match subscription.next().await {
+                Some((i, state)) if i == id => break state,
+                Some(_) => continue,
+                None => bail!("The daemon went away"),
+            }
+        };
+        let (publisher, receiver) = watch::channel(initial);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    event = subscription.next() => match event {
+                        Some((i, state)) if i == id => {
+                            publisher.send_replace(state);
+                        }
+                        Some(_) => {}
+                        None => break,
+                    },
+                    _ = publisher.closed() => break,
+                }
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+/// State changes pushed by the daemon. Starts with the current state of every session.
+pub struct Subscription {
+    lines: Lines<Reader>,
+    // Dropping our half would look like the client going away.
+    _writer: Writer,
+}
+
+impl Subscription {
+    /// The next event, or `None` once the daemon goes away.
+    pub async fn next(&mut self) -> Option<(SessionId, SessionState)> {
+        loop {
+            let line = self.lines.next_line().await.ok()??;
+            if let Ok(Response::Event { id, state }) = serde_json::from_str(&line) {
+                return Some((id, state));
+            }
+        }
+    }
+}
+
+/// The client side of an attached UI: remote sessions that look like local ones, kept up to
+/// date by a single subscription.
+#[derive(Clone)]
+pub struct Remote {
+    client: Client,
+    publishers: Arc<Mutex<HashMap<SessionId, watch::Sender<SessionState>>>>,
+}
+
+impl Remote {
+    /// Lists the daemon's sessions and starts following their state.
+    pub async fn attach(client: Client) -> Result<(Self, Vec<Uhh>)> {
+        let mut subscription = client.subscribe().await?;
+        let entries = client.list().await?;
+        let remote = Self {
+            client,
+            publishers: Arc::default(),
+        };
+        let list = entries
+            .into_iter()
+            .map(|entry| {
+                let session = remote.session(entry.id, entry.state.clone());
+                (session, entry.server, entry.state)
+            })
+            .collect();
+
+        let publishers = remote.publishers.clone();
+        tokio::spawn(async move {
+            while let Some((id, state)) = subscription.next().await {
+                let mut publishers = publishers.lock().unwrap();
+                // Sessions this UI has deleted are gone from the daemon too.
+                publishers.retain(|_, publisher| !publisher.is_closed());
+                if let Some(publisher) = publishers.get(&id) {
+                    publisher.send_replace(state);
+                }
+            }
+            for publisher in publishers.lock().unwrap().values() {
+                publisher.send_modify(|state| {
+                    state.last_error = Some("Lost connection to the daemon".to_string());
+                });
+            }
+        });
+
+        Ok((remote, list))
+    }
+
+    fn session(&self, id: SessionId, state: SessionState) -> Session {
+        let (publisher, receiver) = watch::channel(state);
+        self.publishers.lock().unwrap().insert(id, publisher);
+        Session::remote(self.client.clone(), id, receiver)
+    }
+
+    pub async fn add(&self, server: Server) -> Result<Session> {
+        match self.client.request(&Request::Add { server }).await? {
+            Response::Added { id } => Ok(self.session(id, SessionState::default())),
+            other => bail!("Unexpected response to add: {:?}", other),
+        }
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        self.client.request(&Request::Save).await?;
+        Ok(())
+    }
+}
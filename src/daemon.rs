@@ -0,0 +1,296 @@
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+
+use crate::control::{self, Client};
+use crate::servers::Config;
+
+// Everything but the refusal in `run` needs a Unix domain socket.
+#[cfg(unix)]
+use {
+    crate::control::{send_line, Entry, Request, Response, SessionId},
+    crate::orphans::{self, Source},
+    crate::process,
+    crate::servers::{self, Defaults, Server},
+    crate::ssm::{Session, SessionState},
+    std::sync::atomic::{AtomicU64, Ordering},
+    std::sync::Arc,
+    tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite},
+    tokio::sync::{broadcast, Mutex},
+};
+
+/// Owns every session, so they outlive whichever UI or script asked for them.
+#[cfg(unix)]
+struct Daemon {
+    entries: Mutex<Vec<(SessionId, Session, Server)>>,
+    next_id: AtomicU64,
+    events: broadcast::Sender<(SessionId, SessionState)>,
+    connections_file: PathBuf,
+    defaults: Defaults,
+}
+
+#[cfg(unix)]
+impl Daemon {
+    fn new(connections_file: PathBuf, defaults: Defaults) -> Self {
+        let (events, _) = broadcast::channel(256);
+        Self {
+            entries: Mutex::default(),
+            next_id: AtomicU64::new(1),
+            events,
+            connections_file,
+            defaults,
+        }
+    }
+
+    fn add(&self, entries: &mut Vec<(SessionId, Session, Server)>, server: Server) -> SessionId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let session = Session::new(server.clone(), self.defaults.clone());
+
+        // Fan state changes out to subscribers. Only holds a receiver, so it ends with the actor.
+        let mut state = session.watch();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            while state.changed().await.is_ok() {
+                let current = state.borrow_and_update().clone();
+                let _ = events.send((id, current));
+            }
+        });
+
+        entries.push((id, session, server));
+        id
+    }
+
+    async fn session(&self, id: SessionId) -> Option<Session> {
+        let entries = self.entries.lock().await;
+        entries
+            .iter()
+            .find(|(i, _, _)| *i == id)
+            .map(|(_, session, _)| session.clone())
+    }
+
+    async fn entries(&self) -> Vec<Entry> {
+        self.entries
+            .lock()
+            .await
+            .iter()
+            .map(|(id, session, server)| Entry {
+                id: *id,
+                server: server.clone(),
+                state: session.state(),
+            })
+            .collect()
+    }
+
+    async fn handle(&self, request: Request) -> Result<Response> {
+        let unknown = |id| anyhow::anyhow!("No session with id {}", id);
+        let response = match request {
+            Request::List => Response::Entries {
+                entries: self.entries().await,
+            },
+            Request::Status { id } => Response::Entry {
+                entry: Box::new(
+                    self.entries()
+                        .await
+                        .into_iter()
+                        .find(|entry| entry.id == id)
+                        .ok_or_else(|| unknown(id))?,
+                ),
+            },
+            Request::Start { id } => {
+                self.session(id)
+                    .await
+                    .ok_or_else(|| unknown(id))?
+                    .start()
+                    .await;
+                Response::Ok
+            }
+            Request::Stop { id } => {
+                self.session(id)
+                    .await
+                    .ok_or_else(|| unknown(id))?
+                    .stop()
+                    .await;
+                Response::Ok
+            }
+            Request::Logs { id } => {
+                let session = self.session(id).await.ok_or_else(|| unknown(id))?;
+                Response::Logs {
+                    stdout: session.stdout().await,
+                    stderr: session.stderr().await,
+                }
+            }
+            Request::Add { server } => {
+                let mut entries = self.entries.lock().await;
+                Response::Added {
+                    id: self.add(&mut entries, server),
+                }
+            }
+            Request::Update { id, server } => {
+                let mut entries = self.entries.lock().await;
+                let (_, session, current) = entries
+                    .iter_mut()
+                    .find(|(i, _, _)| *i == id)
+                    .ok_or_else(|| unknown(id))?;
+                session.update(server.clone()).await;
+                *current = server;
+                Response::Ok
+            }
+            Request::Remove { id } => {
+                let removed = {
+                    let mut entries = self.entries.lock().await;
+                    let index = entries
+                        .iter()
+                        .position(|(i, _, _)| *i == id)
+                        .ok_or_else(|| unknown(id))?;
+                    entries.remove(index)
+                };
+                removed.1.shutdown().await;
+                Response::Ok
+            }
+            Request::Save => {
+                let servers: Vec<_> = self
+                    .entries
+                    .lock()
+                    .await
+                    .iter()
+                    .map(|(_, _, server)| server.clone())
+                    .collect();
+                servers::save(&self.connections_file, &self.defaults, &servers).await?;
+                Response::Ok
+            }
+            Request::Subscribe => bail!("Subscribe must be handled by the connection"),
+        };
+        Ok(response)
+    }
+
+    /// Sends the state of every session, then every change after that.
+    async fn stream_events(&self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        let mut events = self.events.subscribe();
+        let mut resync = true;
+        loop {
+            if resync {
+                for entry in self.entries().await {
+                    let event = Response::Event {
+                        id: entry.id,
+                        state: entry.state,
+                    };
+                    send_line(&mut writer, &event).await?;
+                }
+                resync = false;
+            }
+            match events.recv().await {
+                Ok((id, state)) => send_line(&mut writer, &Response::Event { id, state }).await?,
+                // Missed some, so start again from a full picture.
+                Err(broadcast::error::RecvError::Lagged(_)) => resync = true,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    async fn serve(
+        &self,
+        reader: impl AsyncBufRead + Unpin,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> Result<()> {
+        let mut lines = reader.lines();
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str(&line) {
+                Ok(Request::Subscribe) => return self.stream_events(writer).await,
+                Ok(request) => self.handle(request).await,
+                Err(err) => Err(err.into()),
+            };
+            let response = response.unwrap_or_else(|err| Response::Error {
+                message: err.to_string(),
+            });
+            send_line(&mut writer, &response).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn bind(path: &std::path::Path) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    // Anyone who can reach the socket can run tunnels as us, so keep it to ourselves.
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+    // Nobody answered on it, so whatever is there is left over from a crash.
+    let _ = std::fs::remove_file(path);
+    Ok(tokio::net::UnixListener::bind(path)?)
+}
+
+/// Runs headless until signalled, serving the control socket.
+pub async fn run(config: Config, connections_file: PathBuf) -> Result<()> {
+    let path = control::socket_path(&connections_file);
+    if Client::connect(&connections_file).await.is_some() {
+        bail!("A daemon is already listening on {}", path.display());
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (config, connections_file);
+        bail!("The daemon needs Unix domain sockets, which this platform doesn't have");
+    }
+    #[cfg(unix)]
+    {
+        let listener = bind(&path)?;
+        let Config { defaults, servers } = config;
+        let daemon = Arc::new(Daemon::new(connections_file, defaults));
+
+        let found = orphans::scan(&servers);
+        {
+            let mut entries = daemon.entries.lock().await;
+            for server in servers {
+                daemon.add(&mut entries, server);
+            }
+            // Nobody to ask, so take back what we recorded ourselves and leave the rest alone.
+            // A record whose process can't be told apart from a stranger with the same pid
+            // counts as the rest.
+            for orphan in found {
+                match (&orphan.source, orphan.server) {
+                    (Source::Recorded, Some(i)) if orphan.verified => {
+                        entries[i]
+                            .1
+                            .adopt(orphan.foreign(), orphan.record.host_port)
+                            .await;
+                    }
+                    _ => eprintln!(
+                        "Port {} is held by pid {}, which isn't ours to touch",
+                        orphan.record.host_port, orphan.record.pid
+                    ),
+                }
+            }
+        }
+
+        eprintln!("Listening on {}", path.display());
+        let shutdown = process::shutdown_signal();
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                conn = listener.accept() => {
+                    let (stream, _) = conn?;
+                    let daemon = daemon.clone();
+                    tokio::spawn(async move {
+                        let (read, write) = stream.into_split();
+                        let _ = daemon.serve(tokio::io::BufReader::new(read), write).await;
+                    });
+                }
+                _ = &mut shutdown => break,
+            }
+        }
+
+        let _ = std::fs::remove_file(&path);
+        let sessions: Vec<_> = daemon
+            .entries
+            .lock()
+            .await
+            .iter()
+            .map(|(_, session, _)| session.clone())
+            .collect();
+        process::stop_all(sessions).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use anyhow::{bail, Result};
+use home::home_dir;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+use crate::backend::BackendConfig;
+use crate::servers::{self, Server};
+
+/// Prints checks as they go, counting the ones that would stop a tunnel working.
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn section(&self, title: &str) {
+        println!("\n{}", title);
+    }
+
+    fn ok(&self, message: impl Display) {
+        println!("  ok    {}", message);
+    }
+
+    fn warn(&self, message: impl Display) {
+        println!("  warn  {}", message);
+    }
+
+    fn fail(&mut self, message: impl Display) {
+        self.failures += 1;
+        println!("  FAIL  {}", message);
+    }
+}
+
+/// Finds a program the way spawning it would.
+fn which(program: &str) -> Option<PathBuf> {
+    if Path::new(program).components().count() > 1 {
+        return Path::new(program).is_file().then(|| program.into());
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file() || candidate.with_extension("exe").is_file())
+}
+
+/// The first line a program prints about its version, wherever it prints it.
+async fn version(program: &str) -> Option<String> {
+    let output = Command::new(program).arg("--version").output().await.ok()?;
+    let text = if output.stdout.is_empty() {
+        output.stderr
+    } else {
+        output.stdout
+    };
+    let text = String::from_utf8_lossy(&text);
+    text.lines().next().map(|line| line.trim().to_string())
+}
+
+/// Where the aws CLI looks for a shared file, honouring the same overrides it does.
+fn aws_file(var: &str, name: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".aws").join(name)))
+}
+
+/// Every profile named in the shared config and credentials files.
+fn profiles() -> HashSet<String> {
+    let mut profiles = HashSet::new();
+    let files = [
+        (aws_file("AWS_CONFIG_FILE", "config"), true),
+        (
+            aws_file("AWS_SHARED_CREDENTIALS_FILE", "credentials"),
+            false,
+        ),
+    ];
+    for (path, is_config) in files {
+        let Some(contents) = path.and_then(|path| std::fs::read_to_string(path).ok()) else {
+            continue;
+        };
+        for line in contents.lines() {
+            let Some(section) = line
+                .trim()
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            else {
+                continue;
+            };
+            let section = section.trim();
+            // The config file prefixes everything but the default profile, and has other
+            // sections besides.
+            let profile = match (is_config, section.strip_prefix("profile ")) {
+                (true, Some(profile)) => profile.trim(),
+                (true, None) if section == "default" => section,
+                (true, None) => continue,
+                (false, _) => section,
+            };
+            profiles.insert(profile.to_string());
+        }
+    }
+    profiles
+}
+
+/// Whether SSM would accept this as a target: an EC2 instance, a hybrid managed instance, or an
+/// ECS container. Tag selectors are only checked for having a key.
+fn valid_target(target: &str) -> bool {
+    let hex = |s: &str| s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'));
+    if let Some(selector) = target.strip_prefix("tag:") {
+        selector
+            .split_once('=')
+            .is_some_and(|(key, _)| !key.is_empty())
+    } else if let Some(id) = target.strip_prefix("i-") {
+        (id.len() == 8 || id.len() == 17) && hex(id)
+    } else if let Some(id) = target.strip_prefix("mi-") {
+        id.len() == 17 && hex(id)
+    } else {
+        target.starts_with("ecs:")
+    }
+}
+
+async fn check_tools(report: &mut Report, servers: &[Server]) {
+    report.section("Tools");
+    // Only SSM needs these, and a config for other backends shouldn't fail for want of them.
+    let mut programs = if servers.iter().any(|s| s.backend.is_ssm()) {
+        vec!["aws", "session-manager-plugin"]
+    } else {
+        vec![]
+    };
+    for server in servers {
+        let program = match &server.backend {
+            BackendConfig::Ssm => match &server.aws_binary {
+                Some(program) => program.0[0].as_str(),
+                None => continue,
+            },
+            BackendConfig::Ssh(_) => "ssh",
+            BackendConfig::Kubectl(_) => "kubectl",
+            // Can't know what it'll be until it's rendered.
+            BackendConfig::Command(template) if template.program.contains('{') => continue,
+            BackendConfig::Command(template) => template.program.as_str(),
+        };
+        if !programs.contains(&program) {
+            programs.push(program);
+        }
+    }
+    if programs.is_empty() {
+        report.ok("No tools to check");
+    }
+
+    for program in programs {
+        let Some(path) = which(program) else {
+            report.fail(format!("{} isn't on PATH", program));
+            continue;
+        };
+        match program {
+            "aws" => match version(program).await {
+                Some(version) if version.starts_with("aws-cli/1.") => report.warn(format!(
+                    "{} ({}) is v1, v2 is recommended",
+                    version,
+                    path.display()
+                )),
+                Some(version) => report.ok(format!("{} ({})", version, path.display())),
+                None => report.fail(format!(
+                    "{} is on PATH but didn't report a version",
+                    path.display()
+                )),
+            },
+            "session-manager-plugin" => match version(program).await {
+                Some(version) => report.ok(format!(
+                    "session-manager-plugin {} ({})",
+                    version,
+                    path.display()
+                )),
+                None => report.fail(format!(
+                    "{} is on PATH but didn't report a version",
+                    path.display()
+                )),
+            },
+            _ => report.ok(format!("{} ({})", program, path.display())),
+        }
+    }
+}
+
+fn check_profiles(report: &mut Report, servers: &[Server]) {
+    report.section("Profiles");
+    let known = profiles();
+    let mut used: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for server in servers.iter().filter(|s| s.backend.is_ssm()) {
+        used.entry(&server.env).or_default().push(&server.name);
+    }
+    if used.is_empty() {
+        report.ok("No SSM servers, so no profiles needed");
+    }
+    for (profile, names) in used {
+        if known.contains(profile) {
+            report.ok(format!("{} is configured", profile));
+        } else {
+            report.fail(format!(
+                "{} (used by {}) isn't in ~/.aws/config or ~/.aws/credentials",
+                profile,
+                names.join(", ")
+            ));
+        }
+    }
+}
+
+fn check_servers(report: &mut Report, servers: &[Server]) {
+    report.section("Servers");
+    let mut by_port: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+    for server in servers {
+        let before = report.failures;
+        if server.host_port != 0 {
+            by_port
+                .entry(server.host_port)
+                .or_default()
+                .push(&server.name);
+        }
+        if server.host_port > u16::MAX.into() {
+            report.fail(format!(
+                "{}: source port {} isn't a port",
+                server.name, server.host_port
+            ));
+        }
+        if server.dest_port == 0 || server.dest_port > u16::MAX.into() {
+            report.fail(format!(
+                "{}: destination port {} isn't a port",
+                server.name, server.dest_port
+            ));
+        }
+        if server.backend.is_ssm() {
+            if !valid_target(&server.identifier) {
+                report.fail(format!(
+                    "{}: {} doesn't look like an instance ID (i- or mi- then hex) or tag:Key=Value",
+                    server.name, server.identifier
+                ));
+            }
+            for target in &server.failover {
+                if !valid_target(target) || target.starts_with("tag:") {
+                    report.fail(format!(
+                        "{}: failover {} doesn't look like an instance ID",
+                        server.name, target
+                    ));
+                }
+            }
+        }
+        if report.failures == before {
+            report.ok(&server.name);
+        }
+    }
+    for (port, names) in by_port {
+        if names.len() > 1 {
+            report.fail(format!(
+                "Source port {} is used by {}, so only one can run at a time",
+                port,
+                names.join(", ")
+            ));
+        }
+    }
+}
+
+/// Checks everything that has to be right before a tunnel can work, saying what isn't.
+pub async fn run(connections_file: &Path) -> Result<()> {
+    let mut report = Report::default();
+
+    let servers = match servers::load(connections_file).await {
+        Ok(config) => {
+            println!(
+                "{} servers in {}",
+                config.servers.len(),
+                connections_file.display()
+            );
+            config
+                .servers
+                .iter()
+                .map(|server| server.with_defaults(&config.defaults))
+                .collect()
+        }
+        Err(err) => {
+            report.fail(format!(
+                "Couldn't read {}: {}",
+                connections_file.display(),
+                err
+            ));
+            vec![]
+        }
+    };
+
+    check_tools(&mut report, &servers).await;
+    check_profiles(&mut report, &servers);
+    check_servers(&mut report, &servers);
+
+    if report.failures > 0 {
+        println!();
+        bail!("{} problem(s) found", report.failures);
+    }
+    println!("\nAll good");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_ssm_targets() {
+        for target in [
+            "i-0123456789abcdef0",
+            "i-01234567",
+            "mi-0123456789abcdef0",
+            "ecs:prod_3f1c0e4b5a6d4c7e8f9a0b1c2d3e4f5a_3f1c0e4b5a6d4c7e8f9a0b1c2d3e4f5a-1234567890",
+            "tag:Name=bastion-prod",
+            "tag:Role=",
+        ] {
+            assert!(valid_target(target), "{}", target);
+        }
+        for target in [
+            "",
+            "i-0123456789ABCDEF0",
+            "i-0123456789abcdef",
+            "i-0123456789abcdeg0",
+            "mi-01234567",
+            "bastion-prod",
+            "tag:=bastion-prod",
+            "tag:Name",
+        ] {
+            assert!(!valid_target(target), "{}", target);
+        }
+    }
+}
@@ -1,10 +1,19 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use home::home_dir;
 use servers::Server;
-use ssm::Session;
+use ssm::{Session, SessionState};
 use std::path::PathBuf;
 
+mod aws;
+mod backend;
+mod cli;
+mod control;
+mod daemon;
+mod doctor;
+mod orphans;
+mod plugin;
+mod process;
 mod servers;
 mod ssm;
 mod ui;
@@ -12,15 +21,77 @@ mod ui;
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Config {
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     connections_file: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-type Uhh = (Session, Server, bool);
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run sessions in the background, controlled over a Unix socket. The UI attaches to it
+    /// whenever it's running, and tunnels survive the UI going away.
+    Daemon,
+    /// Print the configured servers.
+    List,
+    /// Start tunnels by name or instance id. Without a daemon, they're held open in the
+    /// foreground until interrupted.
+    Up {
+        #[arg(required = true)]
+        servers: Vec<String>,
+    },
+    /// Stop tunnels running in the daemon, by name or instance id.
+    Down {
+        #[arg(required = true)]
+        servers: Vec<String>,
+    },
+    /// Show what every tunnel is up to.
+    Status {
+        /// Print JSON for scripts instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run a command while a tunnel is up, e.g. `exec db -- psql -h localhost -p {port}`. The
+    /// port is substituted for `{port}` and exported as SECURE_CORD_PORT, and the command's exit
+    /// code is ours.
+    Exec {
+        /// Name or instance id of the server.
+        server: String,
+        /// Seconds to wait for the tunnel to be ready.
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Block until a tunnel that's already been started, by the daemon or a UI, accepts
+    /// connections. Exits non-zero if it fails or the timeout runs out first.
+    Wait {
+        /// Name or instance id of the server.
+        server: String,
+        /// Seconds to wait before giving up.
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+    },
+    /// Check the aws CLI, session-manager-plugin, profiles and the connections file for the
+    /// usual mistakes.
+    Doctor,
+}
+
+/// Where secure-cord keeps its own bookkeeping, as opposed to the user's connections file.
+pub fn state_dir() -> PathBuf {
+    home_dir()
+        .expect("Can't get home dir.")
+        .join(".secure-cord")
+}
+
+type Uhh = (Session, Server, SessionState);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let Config { connections_file } = Config::parse();
+    let Config {
+        connections_file,
+        command,
+    } = Config::parse();
 
     let connections_file = match connections_file {
         None => home_dir()
@@ -29,24 +100,36 @@ async fn main() -> Result<()> {
         Some(f) => f,
     };
 
-    let servers = servers::load(&connections_file).await?;
+    match command {
+        Some(Command::Daemon) => {
+            let config = servers::load(&connections_file).await?;
+            return daemon::run(config, connections_file).await;
+        }
+        Some(command) => return cli::run(command, &connections_file).await,
+        None => {}
+    }
+
+    if let Some(client) = control::Client::connect(&connections_file).await {
+        let (remote, mapped) = control::Remote::attach(client).await?;
+        // The daemon applies its own, but the UI still runs the odd aws command itself.
+        let defaults = servers::load(&connections_file)
+            .await
+            .map(|config| config.defaults)
+            .unwrap_or_default();
+        return ui::run(mapped, connections_file, defaults, vec![], Some(remote)).await;
+    }
+
+    let servers::Config { defaults, servers } = servers::load(&connections_file).await?;
+    let orphans = orphans::scan(&servers);
     let mapped: Vec<Uhh> = servers
         .into_iter()
         .map(|s| {
-            (
-                Session::new(
-                    s.identifier.clone(),
-                    s.env.clone(),
-                    s.host_port,
-                    s.dest_port,
-                ),
-                s,
-                false,
-            )
+            let session = Session::new(s.clone(), defaults.clone());
+            (session, s, SessionState::default())
         })
         .collect();
 
-    ui::run(mapped, connections_file).await?;
+    ui::run(mapped, connections_file, defaults, orphans, None).await?;
 
     Ok(())
 }
@@ -0,0 +1,192 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::process::{self, Foreign};
+use crate::servers::Server;
+
+/// What's written to disk for every tunnel process a session is responsible for, so a later run
+/// can find it again if this one dies without cleaning up.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Record {
+    pub pid: u32,
+    pub group: bool,
+    /// The secure-cord process that owns the tunnel. Only a dead owner makes an orphan.
+    pub owner: u32,
+    pub name: String,
+    #[serde(rename = "hostPort")]
+    pub host_port: usize,
+    /// The process's start time, so a pid reused after a reboot isn't mistaken for the tunnel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started: Option<u64>,
+}
+
+impl Record {
+    pub fn new(foreign: Foreign, server: &Server) -> Self {
+        Self {
+            pid: foreign.pid,
+            group: foreign.group,
+            owner: std::process::id(),
+            name: server.name.clone(),
+            host_port: server.host_port,
+            started: process::start_time(foreign.pid),
+        }
+    }
+
+    /// Whether the pid still belongs to the recorded process. `None` when that can't be told,
+    /// because the platform doesn't say or the record predates keeping track.
+    pub fn same_process(&self) -> Option<bool> {
+        match (self.started, process::start_time(self.pid)) {
+            (Some(then), Some(now)) => Some(then == now),
+            _ => None,
+        }
+    }
+
+    fn path(pid: u32) -> PathBuf {
+        crate::state_dir()
+            .join("sessions")
+            .join(format!("{}.json", pid))
+    }
+
+    /// Best effort; a missing record only means a crash would go unnoticed next time.
+    pub fn save(&self) {
+        let path = Self::path(self.pid);
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        if let Ok(json) = serde_json::to_string(self) {
+            let _ = std::fs::write(path, json);
+        }
+    }
+
+    pub fn forget(pid: u32) {
+        let _ = std::fs::remove_file(Self::path(pid));
+    }
+
+    fn load_all() -> Vec<Record> {
+        let Ok(entries) = std::fs::read_dir(crate::state_dir().join("sessions")) else {
+            return vec![];
+        };
+        entries
+            .flatten()
+            .filter_map(|entry| std::fs::read_to_string(entry.path()).ok())
+            .filter_map(|data| serde_json::from_str(&data).ok())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// Recorded by a previous run that is no longer around.
+    Recorded,
+    /// Not recorded, but something is listening on a configured port. Could be anything, so
+    /// only the listening process itself is touched, never its group.
+    Listening,
+}
+
+#[derive(Debug, Clone)]
+pub struct Orphan {
+    pub record: Record,
+    pub source: Source,
+    /// The process is known to be the one that was recorded, not just something with its pid.
+    pub verified: bool,
+    /// The process name, where the platform lets us find it.
+    pub process: Option<String>,
+    /// Index into the server list this orphan most likely belongs to.
+    pub server: Option<usize>,
+}
+
+impl Orphan {
+    /// The process to adopt or kill. Only a verified tunnel gets its whole group signalled, as
+    /// anything else may be a stranger that happens to have the pid.
+    pub fn foreign(&self) -> Foreign {
+        Foreign {
+            pid: self.record.pid,
+            group: self.record.group && self.verified,
+        }
+    }
+
+    /// Checks again that the process is still the one found, since time passes between a scan
+    /// and someone deciding what to do about it.
+    pub fn still_there(&self) -> bool {
+        process::alive(self.record.pid) && self.record.same_process() != Some(false)
+    }
+}
+
+/// Finds tunnels left behind by runs that didn't shut down cleanly, tidying up records of
+/// processes that are long gone along the way.
+pub fn scan(servers: &[Server]) -> Vec<Orphan> {
+    let mut orphans: Vec<Orphan> = vec![];
+    // Groups still looked after by a live secure-cord, whose listeners aren't ours to judge.
+    let mut owned_groups = vec![];
+
+    for record in Record::load_all() {
+        let same = record.same_process();
+        if !process::alive(record.pid) || same == Some(false) {
+            Record::forget(record.pid);
+        } else if process::alive(record.owner) && record.owner != std::process::id() {
+            owned_groups.push(record.pid);
+        } else {
+            let server = servers
+                .iter()
+                .position(|s| {
+                    s.name == record.name && (s.host_port == record.host_port || s.host_port == 0)
+                })
+                .or_else(|| servers.iter().position(|s| s.host_port == record.host_port));
+            orphans.push(Orphan {
+                process: process::name(record.pid),
+                record,
+                source: Source::Recorded,
+                verified: same == Some(true),
+                server,
+            });
+        }
+    }
+
+    for (i, server) in servers.iter().enumerate() {
+        // Auto assigned ports could be anywhere, so only recorded tunnels can be found for those.
+        let Some(pid) = u16::try_from(server.host_port)
+            .ok()
+            .filter(|port| *port != 0)
+            .and_then(process::listener)
+        else {
+            continue;
+        };
+        let group = process::pgid(pid);
+        let known = orphans
+            .iter()
+            .map(|o| o.record.pid)
+            .chain(owned_groups.iter().copied())
+            .any(|known| known == pid || Some(known) == group);
+        if known || pid == std::process::id() {
+            continue;
+        }
+        orphans.push(Orphan {
+            record: Record {
+                pid,
+                group: false,
+                owner: 0,
+                name: server.name.clone(),
+                host_port: server.host_port,
+                started: process::start_time(pid),
+            },
+            source: Source::Listening,
+            // Found just now, but the pid is all there is to go on.
+            verified: false,
+            process: process::name(pid),
+            server: Some(i),
+        });
+    }
+
+    orphans
+}
+
+/// A tunnel for the named server that some live secure-cord is looking after, which is the only
+/// way to learn an automatically assigned port without a daemon to ask.
+pub fn recorded(name: &str) -> Option<Record> {
+    Record::load_all().into_iter().find(|record| {
+        record.name == name
+            && process::alive(record.pid)
+            && record.same_process() != Some(false)
+            && process::alive(record.owner)
+    })
+}
@@ -0,0 +1,83 @@
+/// Something the aws CLI or session-manager-plugin said on stdout about a tunnel, picked out of
+/// the noise.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The aws CLI has an SSM session and is handing it to the plugin.
+    SessionStarted(String),
+    /// The local port is bound, so the tunnel is usable.
+    WaitingForConnections,
+    /// e.g. "Connection accepted for session [alice-0123456789abcdef0]."
+    ConnectionAccepted,
+    /// Not every plugin version says when a connection closes, so counting these against accepted
+    /// ones only gives an approximate number of open connections.
+    ConnectionClosed,
+    /// The plugin is on its way out.
+    SessionEnded,
+    Error(String),
+}
+
+impl Event {
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let lower = line.to_ascii_lowercase();
+        if let Some(id) = line.strip_prefix("Starting session with SessionId:") {
+            Some(Event::SessionStarted(id.trim().to_string()))
+        } else if lower.starts_with("waiting for connections") {
+            Some(Event::WaitingForConnections)
+        } else if lower.starts_with("connection accepted") {
+            Some(Event::ConnectionAccepted)
+        } else if lower.contains("connection closed") || lower.contains("closed connection") {
+            Some(Event::ConnectionClosed)
+        } else if lower.starts_with("exiting session with sessionid") {
+            Some(Event::SessionEnded)
+        } else if lower.contains("failed") || lower.contains("error") {
+            // e.g. "Connection to destination port failed, check SSM Agent logs."
+            Some(Event::Error(line.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_port_forwarding_session() {
+        // What `aws ssm start-session --document-name AWS-StartPortForwardingSession` prints.
+        let lines = [
+            "",
+            "Starting session with SessionId: alice-0a1b2c3d4e5f67890",
+            "Port 5432 opened for sessionId alice-0a1b2c3d4e5f67890.",
+            "Waiting for connections...",
+            "",
+            "Connection accepted for session [alice-0a1b2c3d4e5f67890].",
+            "",
+            "",
+            "Exiting session with sessionId: alice-0a1b2c3d4e5f67890.",
+            "",
+        ];
+        let events: Vec<_> = lines.iter().filter_map(|line| Event::parse(line)).collect();
+        assert_eq!(
+            events,
+            [
+                Event::SessionStarted("alice-0a1b2c3d4e5f67890".to_string()),
+                Event::WaitingForConnections,
+                Event::ConnectionAccepted,
+                Event::SessionEnded,
+            ]
+        );
+    }
+
+    #[test]
+    fn picks_out_errors() {
+        let line = "Connection to destination port failed, check SSM Agent logs.";
+        assert_eq!(Event::parse(line), Some(Event::Error(line.to_string())));
+        let line = "\r\nAn error occurred (TargetNotConnected) when calling the StartSession operation: i-0123456789abcdef0 is not connected.";
+        assert_eq!(
+            Event::parse(line),
+            Some(Event::Error(line.trim().to_string()))
+        );
+    }
+}
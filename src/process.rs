@@ -0,0 +1,294 @@
+use std::io;
+use std::time::Duration;
+use tokio::process::{Child, Command};
+
+use crate::ssm::Session;
+
+/// How long a tunnel gets to exit after being asked nicely, before the whole group is killed.
+pub const STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// Puts the spawned child in its own process group, so session-manager-plugin and friends go
+/// down with it rather than being orphaned holding the port.
+pub fn isolate(command: &mut Command) {
+    #[cfg(unix)]
+    command.process_group(0);
+    command.kill_on_drop(true);
+}
+
+#[cfg(unix)]
+fn send_signal(pid: u32, group: bool, signal: libc::c_int) -> io::Result<()> {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    };
+    let target = if group { -pid } else { pid };
+    // SAFETY: kill has no memory safety preconditions; a negative pid addresses the group.
+    if unsafe { libc::kill(target, signal) } == -1 {
+        let err = io::Error::last_os_error();
+        // The process already being gone is exactly what we wanted.
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Politely asks the child's process group to exit.
+pub fn terminate(child: &mut Child) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(pgid) = child.id() {
+        return send_signal(pgid, true, libc::SIGTERM);
+    }
+    child.start_kill()
+}
+
+/// Forcibly kills the child's process group.
+pub fn kill(child: &mut Child) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(pgid) = child.id() {
+        return send_signal(pgid, true, libc::SIGKILL);
+    }
+    child.start_kill()
+}
+
+/// Kills whatever is left of a group whose leader has already been reaped.
+pub fn reap_group(pgid: u32) {
+    #[cfg(unix)]
+    let _ = send_signal(pgid, true, libc::SIGKILL);
+    #[cfg(not(unix))]
+    let _ = pgid;
+}
+
+/// Whether a process with this pid exists, whoever it belongs to.
+pub fn alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        let Ok(pid) = libc::pid_t::try_from(pid) else {
+            return false;
+        };
+        // SAFETY: signal 0 only checks for existence and permission.
+        let res = unsafe { libc::kill(pid, 0) };
+        res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        false
+    }
+}
+
+/// A tunnel process we didn't spawn, e.g. one left behind by a crashed run, that a session has
+/// taken responsibility for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Foreign {
+    pub pid: u32,
+    /// Whether `pid` leads a process group of its own that should be signalled as a whole.
+    pub group: bool,
+}
+
+impl Foreign {
+    pub fn terminate(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        return send_signal(self.pid, self.group, libc::SIGTERM);
+        #[cfg(not(unix))]
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    pub fn kill(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        return send_signal(self.pid, self.group, libc::SIGKILL);
+        #[cfg(not(unix))]
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    /// Terminates, then kills if it hasn't gone within the grace period.
+    pub async fn stop(self) -> io::Result<()> {
+        self.terminate()?;
+        if tokio::time::timeout(STOP_GRACE, self.exited())
+            .await
+            .is_err()
+        {
+            self.kill()?;
+        }
+        Ok(())
+    }
+
+    /// Resolves once the process is gone. It isn't our child, so all we can do is poll.
+    pub async fn exited(self) {
+        while alive(self.pid) {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+/// The short name of a process, e.g. `session-manager`.
+pub fn name(pid: u32) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+        Some(comm.trim().to_string())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = pid;
+        None
+    }
+}
+
+/// When a process started, in clock ticks since boot. Unlike the pid, which gets reused, this
+/// tells one process apart from another that inherited its pid.
+pub fn start_time(pid: u32) -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // Field 22, counting from the state after the command name, which is field 3.
+        let rest = &stat[stat.rfind(')')? + 1..];
+        rest.split_whitespace().nth(19)?.parse().ok()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = pid;
+        None
+    }
+}
+
+/// The process group a process belongs to.
+pub fn pgid(pid: u32) -> Option<u32> {
+    #[cfg(target_os = "linux")]
+    {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // The command name may contain spaces and parens, so count fields from the last paren:
+        // state, ppid, pgrp.
+        let rest = &stat[stat.rfind(')')? + 1..];
+        rest.split_whitespace().nth(2)?.parse().ok()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = pid;
+        None
+    }
+}
+
+/// The pid of whatever is listening on a local TCP port, found by matching the socket inode in
+/// /proc/net/tcp{,6} against every process's open file descriptors. Only processes we're allowed
+/// to inspect can be found.
+pub fn listener(port: u16) -> Option<u32> {
+    #[cfg(target_os = "linux")]
+    {
+        const LISTEN: &str = "0A";
+        let mut inodes = vec![];
+        for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+            let Ok(contents) = std::fs::read_to_string(table) else {
+                continue;
+            };
+            for line in contents.lines().skip(1) {
+                let fields: Vec<_> = line.split_whitespace().collect();
+                let (Some(local), Some(state), Some(inode)) =
+                    (fields.get(1), fields.get(3), fields.get(9))
+                else {
+                    continue;
+                };
+                let local_port = local
+                    .rsplit(':')
+                    .next()
+                    .and_then(|p| u16::from_str_radix(p, 16).ok());
+                if *state == LISTEN && local_port == Some(port) && *inode != "0" {
+                    inodes.push(format!("socket:[{}]", inode));
+                }
+            }
+        }
+        if inodes.is_empty() {
+            return None;
+        }
+
+        for entry in std::fs::read_dir("/proc").ok()?.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|p| p.parse().ok()) else {
+                continue;
+            };
+            let Ok(fds) = std::fs::read_dir(entry.path().join("fd")) else {
+                continue;
+            };
+            for fd in fds.flatten() {
+                if let Ok(link) = std::fs::read_link(fd.path()) {
+                    if inodes
+                        .iter()
+                        .any(|inode| link.as_os_str() == inode.as_str())
+                    {
+                        return Some(pid);
+                    }
+                }
+            }
+        }
+        None
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = port;
+        None
+    }
+}
+
+/// Resolves on the first signal that should bring the whole app down. The signals are caught from
+/// the moment this is called, not first polled, so call it before spawning anything that a signal
+/// would otherwise leave behind.
+pub fn shutdown_signal() -> impl std::future::Future<Output = ()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut interrupt = signal(SignalKind::interrupt()).expect("Can't listen for SIGINT");
+        let mut terminate = signal(SignalKind::terminate()).expect("Can't listen for SIGTERM");
+        let mut hangup = signal(SignalKind::hangup()).expect("Can't listen for SIGHUP");
+        async move {
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+                _ = hangup.recv() => {}
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let mut interrupt = tokio::signal::windows::ctrl_c().expect("Can't listen for Ctrl-C");
+        async move {
+            interrupt.recv().await;
+        }
+    }
+}
+
+/// Resolves on a signal aimed at us alone, for while a child has the terminal. The terminal's
+/// interrupt reaches the child's process group too, and it's up to the child what that means, so
+/// those are swallowed rather than taking us down. There's no putting the default back, so this is
+/// only for the last thing a run does.
+pub fn termination_signal() -> impl std::future::Future<Output = ()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut interrupt = signal(SignalKind::interrupt()).expect("Can't listen for SIGINT");
+        let mut terminate = signal(SignalKind::terminate()).expect("Can't listen for SIGTERM");
+        let mut hangup = signal(SignalKind::hangup()).expect("Can't listen for SIGHUP");
+        async move {
+            loop {
+                tokio::select! {
+                    _ = interrupt.recv() => {}
+                    _ = terminate.recv() => break,
+                    _ = hangup.recv() => break,
+                }
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let mut interrupt = tokio::signal::windows::ctrl_c().expect("Can't listen for Ctrl-C");
+        async move {
+            while interrupt.recv().await.is_some() {}
+            std::future::pending().await
+        }
+    }
+}
+
+/// Stops every session and waits for their children to be gone.
+pub async fn stop_all(sessions: impl IntoIterator<Item = Session>) {
+    let futs = sessions.into_iter().map(|session| async move {
+        session.shutdown().await;
+    });
+    futures::future::join_all(futs).await;
+}
@@ -1,27 +1,451 @@
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::Path;
-use anyhow::Result;
+use std::time::Duration;
+use tokio::process::Command;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+use crate::backend::BackendConfig;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Server {
+    /// For SSM, either an instance ID or `tag:Key=Value`, which picks a running instance with
+    /// that tag each time the session starts.
     #[serde(rename = "instanceId")]
     pub identifier: String,
+    /// Tried in order when `instanceId` isn't connected or its tunnel dies before it's ready.
+    /// Unused with a tag selector, whose matches are all tried in the same way.
+    #[serde(
+        rename = "failoverInstanceIds",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub failover: Vec<String>,
     pub env: String,
-    #[serde(rename = "sourcePort")]
+    /// `0` (or `"auto"` in jobs.json) means pick a free port each time the session starts.
+    #[serde(rename = "sourcePort", with = "auto_port")]
     pub host_port: usize,
     pub name: String,
     #[serde(rename = "destPort")]
     pub dest_port: usize,
+    /// Forward to this host as seen from the target, rather than the target itself. Handy for
+    /// reaching RDS or ElastiCache through a bastion.
+    #[serde(
+        rename = "remoteHost",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub remote_host: Option<String>,
+    #[serde(
+        rename = "healthCheck",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart: Option<Restart>,
+    #[serde(default, skip_serializing_if = "BackendConfig::is_ssm")]
+    pub backend: BackendConfig,
+    /// Overrides the profile's region for everything run against this server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(rename = "awsBinary", default, skip_serializing_if = "Option::is_none")]
+    pub aws_binary: Option<Program>,
+    /// Appended to `aws ssm start-session`.
+    #[serde(rename = "extraArgs", default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_args: Vec<String>,
+    /// Set in the environment of the tunnel process, whatever the backend.
+    #[serde(
+        rename = "envVars",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub env_vars: BTreeMap<String, String>,
+    /// Assume this role with the profile's credentials and run the tunnel as it, for targets in
+    /// other accounts.
+    #[serde(rename = "roleArn", default, skip_serializing_if = "Option::is_none")]
+    pub role_arn: Option<String>,
+    /// Shows up in CloudTrail; defaults to `secure-cord`.
+    #[serde(
+        rename = "roleSessionName",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub role_session_name: Option<String>,
+    #[serde(
+        rename = "externalId",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub external_id: Option<String>,
+}
+
+impl Server {
+    /// The tag to pick an instance by, when `instanceId` is written as a selector.
+    pub fn tag_selector(&self) -> Option<(&str, &str)> {
+        if !self.backend.is_ssm() {
+            return None;
+        }
+        self.identifier.strip_prefix("tag:")?.split_once('=')
+    }
+
+    /// This server with anything it leaves unset filled in from the defaults. Arguments and
+    /// environment variables are combined, with the server's own winning.
+    pub fn with_defaults(&self, defaults: &Defaults) -> Server {
+        let mut server = self.clone();
+        server.region = server.region.or_else(|| defaults.region.clone());
+        server.aws_binary = server.aws_binary.or_else(|| defaults.aws_binary.clone());
+        server.extra_args = defaults
+            .extra_args
+            .iter()
+            .chain(&self.extra_args)
+            .cloned()
+            .collect();
+        server.env_vars = defaults.env_vars.clone();
+        server.env_vars.extend(self.env_vars.clone());
+        server
+    }
+}
+
+/// What every server falls back on, from the `defaults` section of jobs.json.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Defaults {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(rename = "awsBinary", default, skip_serializing_if = "Option::is_none")]
+    pub aws_binary: Option<Program>,
+    #[serde(rename = "extraArgs", default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_args: Vec<String>,
+    #[serde(
+        rename = "envVars",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub env_vars: BTreeMap<String, String>,
+}
+
+impl Defaults {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A program to run, written as just its name, or as a list of it and the arguments that go in
+/// front of everything else. The list form lets wrappers stand in for a tool, e.g.
+/// `["aws-vault", "exec", "prod", "--", "aws"]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program(pub Vec<String>);
+
+impl Program {
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.0[0]);
+        command.args(&self.0[1..]);
+        command
+    }
+}
+
+impl Serialize for Program {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.as_slice() {
+            [program] => serializer.serialize_str(program),
+            all => all.serialize(serializer),
+        }
+    }
 }
 
-pub async fn load(path: impl AsRef<Path>) -> Result<Vec<Server>> {
+impl<'de> Deserialize<'de> for Program {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Name(String),
+            List(Vec<String>),
+        }
+        match Written::deserialize(deserializer)? {
+            Written::Name(name) => Ok(Program(vec![name])),
+            Written::List(list) if !list.is_empty() => Ok(Program(list)),
+            Written::List(_) => Err(serde::de::Error::invalid_length(0, &"a program to run")),
+        }
+    }
+}
+
+/// When a session whose child has exited on its own should be brought back up.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+/// Restart policy and the exponential backoff used between attempts.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Restart {
+    #[serde(default)]
+    pub policy: RestartPolicy,
+    #[serde(
+        rename = "initialBackoffMs",
+        default = "Restart::default_initial_backoff"
+    )]
+    pub initial_backoff_ms: u64,
+    #[serde(rename = "maxBackoffMs", default = "Restart::default_max_backoff")]
+    pub max_backoff_ms: u64,
+    /// Consecutive attempts before giving up; `None` retries forever.
+    #[serde(rename = "maxRetries", default = "Restart::default_max_retries")]
+    pub max_retries: Option<u32>,
+}
+
+impl Restart {
+    fn default_initial_backoff() -> u64 {
+        1000
+    }
+
+    fn default_max_backoff() -> u64 {
+        60_000
+    }
+
+    fn default_max_retries() -> Option<u32> {
+        Some(10)
+    }
+
+    /// The delay before attempt number `retry` (zero based), with "equal jitter" applied so a
+    /// batch of tunnels dropped by the same blip don't all hammer SSM at the same instant.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let base = self
+            .initial_backoff_ms
+            .saturating_mul(1u64 << retry.min(32))
+            .min(self.max_backoff_ms);
+        let half = base / 2;
+        Duration::from_millis(half + fastrand::u64(0..=half))
+    }
+}
+
+impl Default for Restart {
+    fn default() -> Self {
+        Self {
+            policy: RestartPolicy::default(),
+            initial_backoff_ms: Self::default_initial_backoff(),
+            max_backoff_ms: Self::default_max_backoff(),
+            max_retries: Self::default_max_retries(),
+        }
+    }
+}
+
+/// How often, and how patiently, the forwarded local port is probed for readiness.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HealthCheck {
+    #[serde(rename = "intervalMs", default = "HealthCheck::default_interval")]
+    pub interval_ms: u64,
+    #[serde(rename = "timeoutMs", default = "HealthCheck::default_timeout")]
+    pub timeout_ms: u64,
+    /// How long a fresh tunnel may refuse connections before it's considered unhealthy.
+    #[serde(
+        rename = "startupGraceMs",
+        default = "HealthCheck::default_startup_grace"
+    )]
+    pub startup_grace_ms: u64,
+}
+
+impl HealthCheck {
+    fn default_interval() -> u64 {
+        2000
+    }
+
+    fn default_timeout() -> u64 {
+        1000
+    }
+
+    fn default_startup_grace() -> u64 {
+        30_000
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn startup_grace(&self) -> Duration {
+        Duration::from_millis(self.startup_grace_ms)
+    }
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            interval_ms: Self::default_interval(),
+            timeout_ms: Self::default_timeout(),
+            startup_grace_ms: Self::default_startup_grace(),
+        }
+    }
+}
+
+/// Lets `sourcePort` be written as `"auto"`, which is how an automatic port is saved back out.
+mod auto_port {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(usize),
+        Word(String),
+    }
+
+    pub fn serialize<S: Serializer>(port: &usize, serializer: S) -> Result<S::Ok, S::Error> {
+        match port {
+            0 => serializer.serialize_str("auto"),
+            port => serializer.serialize_u64(*port as u64),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+        match Port::deserialize(deserializer)? {
+            Port::Number(port) => Ok(port),
+            Port::Word(word) if word.eq_ignore_ascii_case("auto") => Ok(0),
+            Port::Word(word) => Err(de::Error::invalid_value(
+                de::Unexpected::Str(&word),
+                &"a port number or \"auto\"",
+            )),
+        }
+    }
+}
+
+/// Everything in jobs.json.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Config {
+    #[serde(default, skip_serializing_if = "Defaults::is_empty")]
+    pub defaults: Defaults,
+    pub servers: Vec<Server>,
+}
+
+/// Reads jobs.json, which is either a plain list of servers or an object with defaults too.
+pub async fn load(path: impl AsRef<Path>) -> Result<Config> {
     let data = tokio::fs::read_to_string(path).await?;
-    let entries: Vec<Server> = serde_json::from_str(&data)?;
-    Ok(entries)
+    // Decide on the shape up front, so a typo gets a real error rather than an untagged enum's
+    // shrug.
+    if data.trim_start().starts_with('[') {
+        let servers: Vec<Server> = serde_json::from_str(&data)?;
+        return Ok(Config {
+            defaults: Defaults::default(),
+            servers,
+        });
+    }
+    Ok(serde_json::from_str(&data)?)
 }
 
-pub async fn save(path: impl AsRef<Path>, servers: &[Server]) -> Result<()> {
-    let json = serde_json::to_string_pretty(servers)?;
+/// Writes jobs.json back out, sticking to the plain list until there are defaults to keep.
+pub async fn save(path: impl AsRef<Path>, defaults: &Defaults, servers: &[Server]) -> Result<()> {
+    let json = if defaults.is_empty() {
+        serde_json::to_string_pretty(servers)?
+    } else {
+        serde_json::to_string_pretty(&Config {
+            defaults: defaults.clone(),
+            servers: servers.to_vec(),
+        })?
+    };
     tokio::fs::write(path, json).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(source_port: &str) -> serde_json::Result<Server> {
+        serde_json::from_str(&format!(
+            r#"{{"instanceId":"i-0123456789abcdef0","env":"dev","sourcePort":{},"name":"db","destPort":5432}}"#,
+            source_port
+        ))
+    }
+
+    #[test]
+    fn source_port_can_be_auto() {
+        assert_eq!(server("5432").unwrap().host_port, 5432);
+        assert_eq!(server(r#""auto""#).unwrap().host_port, 0);
+        assert_eq!(server(r#""Auto""#).unwrap().host_port, 0);
+        assert_eq!(server("0").unwrap().host_port, 0);
+        assert!(server(r#""5432""#).is_err());
+        assert!(server("-1").is_err());
+
+        let json = serde_json::to_value(server(r#""auto""#).unwrap()).unwrap();
+        assert_eq!(json["sourcePort"], "auto");
+        let json = serde_json::to_value(server("5432").unwrap()).unwrap();
+        assert_eq!(json["sourcePort"], 5432);
+    }
+
+    #[test]
+    fn program_is_a_name_or_a_list() {
+        let program: Program = serde_json::from_str(r#""/usr/local/bin/aws""#).unwrap();
+        assert_eq!(program, Program(vec!["/usr/local/bin/aws".to_string()]));
+        let program: Program =
+            serde_json::from_str(r#"["aws-vault", "exec", "prod", "--", "aws"]"#).unwrap();
+        assert_eq!(program.0, ["aws-vault", "exec", "prod", "--", "aws"]);
+        assert!(serde_json::from_str::<Program>("[]").is_err());
+        assert!(serde_json::from_str::<Program>("42").is_err());
+
+        // Written back the way it was read.
+        let json = serde_json::to_string(&Program(vec!["aws".to_string()])).unwrap();
+        assert_eq!(json, r#""aws""#);
+        assert_eq!(
+            serde_json::to_string(&program).unwrap(),
+            r#"["aws-vault","exec","prod","--","aws"]"#
+        );
+    }
+
+    async fn load_str(name: &str, data: &str) -> Result<Config> {
+        let path = std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()));
+        tokio::fs::write(&path, data).await?;
+        let config = load(&path).await;
+        let _ = tokio::fs::remove_file(&path).await;
+        config
+    }
+
+    #[tokio::test]
+    async fn loads_a_list_or_an_object() {
+        let list = r#"[
+            {"instanceId":"i-0123456789abcdef0","env":"dev","sourcePort":5432,"name":"db","destPort":5432}
+        ]"#;
+        let config = load_str("secure-cord-list", list).await.unwrap();
+        assert!(config.defaults.is_empty());
+        assert_eq!(config.servers.len(), 1);
+
+        let object = r#"{
+            "defaults": {"region": "eu-west-1", "awsBinary": ["aws-vault", "exec", "prod", "--", "aws"]},
+            "servers": [
+                {"instanceId":"i-0123456789abcdef0","env":"dev","sourcePort":"auto","name":"db","destPort":5432}
+            ]
+        }"#;
+        let config = load_str("secure-cord-object", object).await.unwrap();
+        assert_eq!(config.defaults.region.as_deref(), Some("eu-west-1"));
+        assert_eq!(config.defaults.aws_binary.unwrap().0.len(), 5);
+        assert_eq!(config.servers[0].host_port, 0);
+
+        // A typo is reported as such, not as matching neither shape.
+        let typo = r#"{"defaults": {}, "server": []}"#;
+        let error = load_str("secure-cord-typo", typo).await.unwrap_err();
+        assert!(error.to_string().contains("servers"), "{}", error);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_with_jitter() {
+        let restart = Restart {
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 10_000,
+            ..Restart::default()
+        };
+        for (retry, base) in [(0, 1000), (1, 2000), (2, 4000), (3, 8000), (4, 10_000)] {
+            for _ in 0..100 {
+                let delay = restart.backoff(retry).as_millis() as u64;
+                assert!((base / 2..=base).contains(&delay), "{}: {}", retry, delay);
+            }
+        }
+        // Far past the point where the doubling would overflow.
+        for retry in [32, 63, 64, u32::MAX] {
+            let delay = restart.backoff(retry).as_millis() as u64;
+            assert!((5000..=10_000).contains(&delay), "{}: {}", retry, delay);
+        }
+    }
+}
@@ -1,76 +1,754 @@
-use futures::future::Either;
+use futures::future::{BoxFuture, Either};
+use serde::{Deserialize, Serialize};
 use std::process::ExitStatus;
+use std::time::{Duration, SystemTime};
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::{Child, ChildStderr, ChildStdout, Command};
-use tokio::sync::{mpsc, oneshot};
+use tokio::net::TcpStream;
+use tokio::process::{Child, ChildStderr, ChildStdout};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+
+use crate::aws::{self, Credentials, Problem};
+use crate::control::{self, Request, Response, SessionId};
+use crate::orphans::Record;
+use crate::plugin::Event;
+use crate::process::{self, Foreign};
+use crate::servers::{Defaults, RestartPolicy, Server};
+
+/// Where a session is in its lifecycle. The actor owns the transitions; everyone else just watches.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Phase {
+    /// Never started, or stopped on request.
+    #[default]
+    Stopped,
+    /// Getting credentials for the server's role before there's anything to spawn.
+    AssumingRole,
+    /// Looking for an instance with the server's tag.
+    Resolving,
+    /// The child is up, but the forwarded port hasn't answered yet.
+    Starting,
+    Ready,
+    /// The port stopped answering, or never did within the startup grace period.
+    Unhealthy,
+    /// Waiting out a backoff before the next automatic restart.
+    Reconnecting,
+    /// Asked the child to die, waiting for it to do so.
+    Stopping,
+    /// The child went away on its own. `None` if it was killed by a signal.
+    Exited(Option<i32>),
+    FailedToSpawn(String),
+    /// Something already holds the local port, so there was no point spawning.
+    PortInUse {
+        pid: Option<u32>,
+        process: Option<String>,
+    },
+}
+
+impl Phase {
+    /// Whether there's a child, or the promise of one, that a stop would affect.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self,
+            Phase::AssumingRole
+                | Phase::Resolving
+                | Phase::Starting
+                | Phase::Ready
+                | Phase::Unhealthy
+                | Phase::Reconnecting
+        )
+    }
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Stopped => write!(f, "Stopped"),
+            Phase::AssumingRole => write!(f, "Assuming role"),
+            Phase::Resolving => write!(f, "Resolving target"),
+            Phase::Starting => write!(f, "Starting"),
+            Phase::Ready => write!(f, "Ready"),
+            Phase::Unhealthy => write!(f, "Unhealthy"),
+            Phase::Reconnecting => write!(f, "Reconnecting"),
+            Phase::Stopping => write!(f, "Stopping"),
+            Phase::Exited(Some(code)) => write!(f, "Exited ({})", code),
+            Phase::Exited(None) => write!(f, "Killed"),
+            Phase::FailedToSpawn(_) => write!(f, "Failed to spawn"),
+            Phase::PortInUse { .. } => write!(f, "Port in use"),
+        }
+    }
+}
+
+/// A snapshot of a session, published by the actor whenever something observable changes.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct SessionState {
+    pub phase: Phase,
+    /// Consecutive automatic restarts since the session was last ready or touched by the user.
+    pub retries: u32,
+    /// The tunnel process was inherited from an earlier run rather than spawned by this one.
+    pub adopted: bool,
+    /// The local port of the current or most recent tunnel, which for an automatically assigned
+    /// port is only known once the session has started.
+    pub local_port: Option<usize>,
+    /// Where the current or most recent tunnel went, which may be a failover instance or one a
+    /// tag selector picked rather than the configured one.
+    pub target: Option<String>,
+    /// The SSM session the aws CLI said it started, for finding it in the console or audit trail.
+    pub session_id: Option<String>,
+    /// Connections the plugin has accepted and not yet reported closed. Only approximate, as not
+    /// every plugin version reports closing them.
+    pub connections: usize,
+    /// When a connection was last accepted or closed.
+    pub last_activity: Option<SystemTime>,
+    /// The most recent line the child wrote to stderr, or why it couldn't be spawned.
+    pub last_error: Option<String>,
+    /// What went wrong, when the output said something we recognise.
+    pub problem: Option<Problem>,
+    /// Number of lines captured from the child so far, so viewers know when to refetch output.
+    pub output_lines: usize,
+}
+
+impl SessionState {
+    /// The phase, plus whatever else is worth knowing at a glance.
+    pub fn label(&self) -> String {
+        let mut label = self.phase.to_string();
+        if self.adopted {
+            label.push_str(" (adopted)");
+        }
+        if self.retries > 0 {
+            label.push_str(&format!(" (retry {})", self.retries));
+        }
+        label
+    }
+}
 
 #[allow(unused)]
 enum SessionMessage {
     Start,
     Stop,
-    Healthy(oneshot::Sender<bool>),
     Stdout(oneshot::Sender<Vec<String>>),
     Stderr(oneshot::Sender<Vec<String>>),
-    UpdateDetails {
-        target: String,
-        env: String,
-        host_port: usize,
-        dest_port: usize,
-    },
+    UpdateDetails(Box<Server>),
+    /// Take responsibility for a tunnel process left behind by an earlier run.
+    Adopt(Foreign, usize),
+    /// Stop for good; replied to once the child is gone and the actor is about to exit.
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// A child we spawned, and the pipes its output comes in on.
+struct Spawned {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    stderr: BufReader<ChildStderr>,
 }
 
-#[allow(unused)]
 enum SessionStatus {
     Fresh,
-    Running(Child, BufReader<ChildStdout>, BufReader<ChildStderr>),
-    Stopped(Result<ExitStatus, std::io::Error>),
+    /// Boxed, as it dwarfs the other variants.
+    Running(Box<Spawned>),
+    /// Someone else's process we're minding. No stdio, and no exit status when it goes.
+    Adopted(Foreign),
 }
 
 struct SessionActor {
     reciever: mpsc::Receiver<SessionMessage>,
+    publisher: watch::Sender<SessionState>,
     status: SessionStatus,
-    target: String,
-    host_port: usize,
-    dest_port: usize,
+    phase: Phase,
+    last_error: Option<String>,
+    problem: Option<Problem>,
+    server: Server,
+    defaults: Defaults,
+    /// The port actually in use, which differs from the configured one when that is `0`/auto.
+    local_port: Option<usize>,
+    started_at: Instant,
+    /// The next pending readiness probe, if the session is running.
+    probe: Option<BoxFuture<'static, bool>>,
+    retries: u32,
+    /// Pending backoff before the next automatic restart.
+    restart: Option<BoxFuture<'static, ()>>,
+    /// The process group of the current child, kept so stragglers can be reaped after it exits.
+    pgid: Option<u32>,
+    /// Armed while stopping; escalates to killing the group if the child ignores the request.
+    kill_deadline: Option<BoxFuture<'static, ()>>,
+    /// Credentials for the server's role, and when they run out.
+    credentials: Option<(Credentials, Instant)>,
+    /// Fetching credentials, either so the session can start or to stay ahead of expiry.
+    assume: Option<BoxFuture<'static, anyhow::Result<Credentials>>>,
+    /// Instances to try this time round, in order, and which one is in use.
+    candidates: Vec<String>,
+    target: Option<String>,
+    resolve: Option<BoxFuture<'static, anyhow::Result<Vec<String>>>>,
+    session_id: Option<String>,
+    connections: usize,
+    last_activity: Option<SystemTime>,
+    /// Telling AWS the session is over. Shutdown waits for it, so it isn't cut short.
+    closing: Option<BoxFuture<'static, anyhow::Result<()>>>,
+    shutdown: Vec<oneshot::Sender<()>>,
     stdout: Vec<String>,
     stderr: Vec<String>,
-    env: String,
+}
+
+/// How long before assumed role credentials expire that they're replaced.
+pub const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Checks the local port is free by briefly binding it ourselves, and if it isn't, tries to find
+/// out who has it.
+fn port_conflict(port: usize) -> Option<Phase> {
+    let port = u16::try_from(port).ok()?;
+    match std::net::TcpListener::bind(("127.0.0.1", port)) {
+        Ok(_) => None,
+        Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => {
+            let pid = process::listener(port);
+            Some(Phase::PortInUse {
+                pid,
+                process: pid.and_then(process::name),
+            })
+        }
+        // Not our problem to diagnose; let the backend have a go and report whatever it hits.
+        Err(_) => None,
+    }
+}
+
+/// Asks the OS for a port nobody is using right now. There's a window between dropping the
+/// listener and the backend binding it, but nothing better is possible without handing it a
+/// socket.
+fn free_port() -> std::io::Result<usize> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0))?;
+    Ok(listener.local_addr()?.port().into())
+}
+
+/// Attempts a TCP connection to the forwarded port, giving up after `timeout`.
+pub async fn probe(port: usize, timeout: Duration) -> bool {
+    let Ok(port) = u16::try_from(port) else {
+        return false;
+    };
+    matches!(
+        tokio::time::timeout(timeout, TcpStream::connect(("127.0.0.1", port))).await,
+        Ok(Ok(_))
+    )
 }
 
 impl SessionActor {
     fn new(
         reciever: mpsc::Receiver<SessionMessage>,
-        target: String,
-        env: String,
-        host_port: usize,
-        dest_port: usize,
+        publisher: watch::Sender<SessionState>,
+        server: Server,
+        defaults: Defaults,
     ) -> Self {
         Self {
             reciever,
+            publisher,
             status: SessionStatus::Fresh,
-            target,
-            host_port,
-            dest_port,
-            env,
+            phase: Phase::Stopped,
+            last_error: None,
+            problem: None,
+            server,
+            defaults,
+            local_port: None,
+            started_at: Instant::now(),
+            probe: None,
+            retries: 0,
+            restart: None,
+            pgid: None,
+            kill_deadline: None,
+            credentials: None,
+            assume: None,
+            candidates: vec![],
+            target: None,
+            resolve: None,
+            session_id: None,
+            connections: 0,
+            last_activity: None,
+            closing: None,
+            shutdown: vec![],
             stdout: vec![],
             stderr: vec![],
         }
     }
 
-    fn terminate(&mut self) {
-        match std::mem::replace(&mut self.status, SessionStatus::Fresh) {
-            SessionStatus::Running(mut child, _, _) => {
-                tokio::spawn(async move {
-                    if let Err(e) = child.kill().await {
-                        // TODO: HAndle?
-                        //eprintln!("Failed to kill child process: {}", e);
+    fn state(&self) -> SessionState {
+        SessionState {
+            phase: self.phase.clone(),
+            retries: self.retries,
+            adopted: matches!(self.status, SessionStatus::Adopted(_)),
+            local_port: self.local_port,
+            target: self.target.clone(),
+            session_id: self.session_id.clone(),
+            connections: self.connections,
+            last_activity: self.last_activity,
+            last_error: self.last_error.clone(),
+            problem: self.problem.clone(),
+            output_lines: self.stdout.len() + self.stderr.len(),
+        }
+    }
+
+    /// Pushes the current state out to subscribers, only waking them if it actually differs.
+    fn publish(&self) {
+        let state = self.state();
+        self.publisher.send_if_modified(|current| {
+            if *current == state {
+                false
+            } else {
+                *current = state;
+                true
+            }
+        });
+    }
+
+    /// The configured server, with the defaults, an automatically assigned port and the chosen
+    /// target filled in.
+    fn effective(&self) -> Server {
+        let mut server = self.server.with_defaults(&self.defaults);
+        if let Some(port) = self.local_port {
+            server.host_port = port;
+        }
+        if let Some(target) = &self.target {
+            server.identifier = target.clone();
+        }
+        server
+    }
+
+    fn schedule_probe(&mut self, delay: Duration) {
+        let port = self.local_port.unwrap_or(self.server.host_port);
+        let timeout = self
+            .server
+            .health_check
+            .clone()
+            .unwrap_or_default()
+            .timeout();
+        self.probe = Some(Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            probe(port, timeout).await
+        }));
+    }
+
+    fn on_probe(&mut self, ok: bool) {
+        self.probe = None;
+        if !matches!(
+            self.phase,
+            Phase::Starting | Phase::Ready | Phase::Unhealthy
+        ) {
+            return;
+        }
+        let check = self.server.health_check.clone().unwrap_or_default();
+        self.phase = match (&self.phase, ok) {
+            (_, true) => {
+                self.retries = 0;
+                self.problem = None;
+                Phase::Ready
+            }
+            (Phase::Starting, false) if self.started_at.elapsed() < check.startup_grace() => {
+                Phase::Starting
+            }
+            (_, false) => Phase::Unhealthy,
+        };
+        self.schedule_probe(check.interval());
+    }
+
+    /// Settles the phase once the child is gone, and arms the backoff if it should come back.
+    /// `Ok(None)` means the process went away but we couldn't learn how.
+    fn on_exit(&mut self, status: Result<Option<ExitStatus>, std::io::Error>) {
+        if let SessionStatus::Adopted(foreign) = &self.status {
+            Record::forget(foreign.pid);
+        }
+        self.status = SessionStatus::Fresh;
+        self.probe = None;
+        self.kill_deadline = None;
+        self.connections = 0;
+        // Whatever comes next checks the credentials for itself.
+        self.assume = None;
+        if let Some(pgid) = self.pgid.take() {
+            process::reap_group(pgid);
+            Record::forget(pgid);
+        }
+        if self.phase == Phase::Stopping {
+            self.phase = Phase::Stopped;
+            return;
+        }
+
+        // Dying before ever being ready, or the agent not being there, says more about the
+        // instance than the tunnel, so move on to the next one straight away.
+        let unreachable =
+            self.phase == Phase::Starting || self.problem == Some(Problem::TargetNotConnected);
+        let next = self
+            .target
+            .as_ref()
+            .and_then(|target| self.candidates.iter().position(|c| c == target))
+            .and_then(|i| self.candidates.get(i + 1))
+            .cloned();
+        if let (true, Some(next)) = (unreachable, next) {
+            self.problem = None;
+            self.target = Some(next);
+            self.launch();
+            return;
+        }
+
+        let failed = !matches!(&status, Ok(Some(status)) if status.success());
+        self.phase = match status {
+            Ok(status) => Phase::Exited(status.and_then(|status| status.code())),
+            Err(err) => {
+                self.last_error = Some(err.to_string());
+                Phase::Exited(None)
+            }
+        };
+        self.schedule_restart(failed);
+    }
+
+    fn schedule_restart(&mut self, failed: bool) {
+        // Retrying won't fix a bad login or a typo, it only buries the error under attempts.
+        if self.problem.as_ref().is_some_and(|p| !p.is_transient()) {
+            return;
+        }
+        let restart = self.server.restart.clone().unwrap_or_default();
+        let wanted = match restart.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Always => true,
+        };
+        let exhausted = restart.max_retries.is_some_and(|max| self.retries >= max);
+        if wanted && !exhausted {
+            let delay = restart.backoff(self.retries);
+            self.retries += 1;
+            self.phase = Phase::Reconnecting;
+            self.restart = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+    }
+
+    /// Fetches credentials for the server's role once `delay` has passed.
+    fn assume_role(&mut self, delay: Duration) {
+        let server = self.server.with_defaults(&self.defaults);
+        self.assume = Some(Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            aws::assume_role(&server).await
+        }));
+    }
+
+    /// Arranges for the credentials to be replaced a little before they run out. A running
+    /// tunnel doesn't need them again, but restarts and anything else run against it do.
+    fn schedule_refresh(&mut self) {
+        if let Some((_, expires)) = &self.credentials {
+            let delay = expires.saturating_duration_since(Instant::now() + REFRESH_MARGIN);
+            self.assume_role(delay);
+        }
+    }
+
+    fn on_assumed(&mut self, res: anyhow::Result<Credentials>) {
+        self.assume = None;
+        match res {
+            Ok(credentials) => {
+                self.credentials = Some((credentials, Instant::now() + aws::ROLE_DURATION));
+                if self.phase == Phase::AssumingRole {
+                    self.spawn();
+                } else if self.phase.is_active() {
+                    self.schedule_refresh();
+                }
+            }
+            Err(err) => {
+                let message = format!("Couldn't assume role: {}", err);
+                if let Some(problem) = Problem::classify(&message) {
+                    self.problem = Some(problem);
+                }
+                self.last_error = Some(message.clone());
+                if self.phase == Phase::AssumingRole {
+                    self.phase = Phase::FailedToSpawn(message);
+                    self.schedule_restart(true);
+                } else if self.phase.is_active() {
+                    // The old ones are still good for a while, so just have another go.
+                    self.assume_role(Duration::from_secs(60));
+                }
+            }
+        }
+    }
+
+    /// Looks up instances for the tag selector, with the role's credentials if there is one.
+    fn resolve(&mut self) {
+        let server = self.server.with_defaults(&self.defaults);
+        let credentials = self.credentials.as_ref().map(|(c, _)| c.clone());
+        self.phase = Phase::Resolving;
+        self.resolve = Some(Box::pin(async move {
+            let selector = server.tag_selector().unwrap();
+            aws::resolve_tag(&server, selector, credentials.as_ref()).await
+        }));
+    }
+
+    fn on_resolved(&mut self, res: anyhow::Result<Vec<String>>) {
+        self.resolve = None;
+        if self.phase != Phase::Resolving {
+            return;
+        }
+        match res {
+            Ok(candidates) => {
+                self.target = candidates.first().cloned();
+                self.candidates = candidates;
+                self.launch();
+            }
+            Err(err) => {
+                let message = err.to_string();
+                if let Some(problem) = Problem::classify(&message) {
+                    self.problem = Some(problem);
+                }
+                self.last_error = Some(message.clone());
+                self.phase = Phase::FailedToSpawn(message);
+                self.schedule_restart(true);
+            }
+        }
+    }
+
+    /// Gets whatever has to be looked up before the child can be spawned, then spawns it. Each
+    /// attempt resolves a tag selector afresh, as the last instance may have been replaced.
+    fn spawn(&mut self) {
+        let fresh = self
+            .credentials
+            .as_ref()
+            .is_some_and(|(_, expires)| *expires > Instant::now() + REFRESH_MARGIN);
+        if self.server.role_arn.is_some() && !fresh {
+            self.phase = Phase::AssumingRole;
+            self.assume_role(Duration::ZERO);
+            return;
+        }
+        if self.server.tag_selector().is_some() {
+            self.resolve();
+            return;
+        }
+        self.candidates = std::iter::once(&self.server.identifier)
+            .chain(&self.server.failover)
+            .cloned()
+            .collect();
+        self.target = Some(self.server.identifier.clone());
+        self.launch();
+    }
+
+    fn launch(&mut self) {
+        if self.server.host_port == 0 {
+            // Keep an assigned port across restarts so clients can reconnect, unless someone
+            // else has grabbed it in the meantime.
+            let reusable = self
+                .local_port
+                .is_some_and(|port| port_conflict(port).is_none());
+            if !reusable {
+                match free_port() {
+                    Ok(port) => self.local_port = Some(port),
+                    Err(err) => {
+                        self.last_error = Some(format!("Couldn't find a free port: {}", err));
+                        self.phase = Phase::FailedToSpawn(err.to_string());
+                        self.schedule_restart(true);
+                        return;
                     }
-                });
+                }
+            }
+        } else {
+            self.local_port = Some(self.server.host_port);
+        }
+        let server = self.effective();
 
-                // TODO: Probably use the proper stopped state.
-                self.status = SessionStatus::Fresh;
+        if let Some(conflict) = port_conflict(server.host_port) {
+            let holder = match &conflict {
+                Phase::PortInUse {
+                    pid: Some(pid),
+                    process,
+                } => format!("pid {} ({})", pid, process.as_deref().unwrap_or("unknown")),
+                _ => "another process".to_string(),
+            };
+            self.last_error = Some(format!(
+                "Port {} is already in use by {}",
+                server.host_port, holder
+            ));
+            self.phase = conflict;
+            self.schedule_restart(true);
+            return;
+        }
+
+        let mut command = server.backend.as_backend().command(&server);
+        command.envs(&server.env_vars);
+        if let Some((credentials, _)) = &self.credentials {
+            credentials.apply(&mut command);
+        }
+        process::isolate(&mut command);
+        command.stdout(std::process::Stdio::piped());
+        command.stderr(std::process::Stdio::piped());
+        let res = command.spawn();
+        match res {
+            Ok(mut child) => {
+                let stdout = BufReader::new(child.stdout.take().unwrap());
+                let stderr = BufReader::new(child.stderr.take().unwrap());
+                self.pgid = child.id();
+                if let Some(pgid) = self.pgid {
+                    let foreign = Foreign {
+                        pid: pgid,
+                        group: true,
+                    };
+                    Record::new(foreign, &server).save();
+                }
+                self.status = SessionStatus::Running(Box::new(Spawned {
+                    child,
+                    stdout,
+                    stderr,
+                }));
+                self.session_id = None;
+                self.connections = 0;
+                self.phase = Phase::Starting;
+                self.started_at = Instant::now();
+                // The plugin usually takes a moment to bind, so don't bother probing instantly.
+                self.schedule_probe(Duration::from_millis(500));
+                self.schedule_refresh();
+            }
+            Err(err) => {
+                self.last_error = Some(err.to_string());
+                self.phase = Phase::FailedToSpawn(err.to_string());
+                self.schedule_restart(true);
             }
-            other_state => self.status = other_state,
+        }
+    }
+
+    /// Ends the SSM session on AWS's side too, so it doesn't linger until it times out.
+    fn close_session(&mut self) {
+        let Some(session_id) = self.session_id.clone() else {
+            return;
+        };
+        let server = self.effective();
+        let credentials = self.credentials.as_ref().map(|(c, _)| c.clone());
+        self.closing = Some(Box::pin(async move {
+            let close = aws::terminate_session(&server, &session_id, credentials.as_ref());
+            let res = match tokio::time::timeout(Duration::from_secs(10), close).await {
+                Ok(res) => res,
+                Err(_) => Err(anyhow::anyhow!("timed out")),
+            };
+            res.map_err(|err| anyhow::anyhow!("Couldn't terminate session {}: {}", session_id, err))
+        }));
+    }
+
+    fn on_closed(&mut self, res: anyhow::Result<()>) {
+        self.closing = None;
+        if let Err(err) = res {
+            self.last_error = Some(err.to_string());
+        }
+    }
+
+    /// Asks the child to die; the phase settles on `Stopped` once `wait` confirms it has.
+    fn terminate(&mut self) {
+        self.restart = None;
+        self.retries = 0;
+        self.probe = None;
+        self.assume = None;
+        self.resolve = None;
+        match &mut self.status {
+            SessionStatus::Running(spawned) => {
+                if self.phase == Phase::Stopping {
+                    return;
+                }
+                if let Err(err) = process::terminate(&mut spawned.child) {
+                    self.last_error = Some(err.to_string());
+                }
+                self.phase = Phase::Stopping;
+                self.kill_deadline = Some(Box::pin(tokio::time::sleep(process::STOP_GRACE)));
+                self.close_session();
+            }
+            SessionStatus::Adopted(foreign) => {
+                if self.phase == Phase::Stopping {
+                    return;
+                }
+                if let Err(err) = foreign.terminate() {
+                    self.last_error = Some(err.to_string());
+                }
+                self.phase = Phase::Stopping;
+                self.kill_deadline = Some(Box::pin(tokio::time::sleep(process::STOP_GRACE)));
+            }
+            SessionStatus::Fresh => {
+                if matches!(
+                    self.phase,
+                    Phase::Reconnecting | Phase::AssumingRole | Phase::Resolving
+                ) {
+                    self.phase = Phase::Stopped;
+                }
+            }
+        }
+    }
+
+    fn on_stdout(&mut self, line: String) {
+        match Event::parse(&line) {
+            Some(Event::SessionStarted(id)) => self.session_id = Some(id),
+            // No need to wait for a probe to find out. Nor to probe any more: the plugin holds the
+            // port for as long as it lives, and each probe would show up as a connection.
+            Some(Event::WaitingForConnections) => {
+                self.probe = None;
+                if matches!(self.phase, Phase::Starting | Phase::Unhealthy) {
+                    self.phase = Phase::Ready;
+                    self.retries = 0;
+                    self.problem = None;
+                }
+            }
+            Some(Event::ConnectionAccepted) => {
+                self.connections += 1;
+                self.last_activity = Some(SystemTime::now());
+            }
+            Some(Event::ConnectionClosed) => {
+                self.connections = self.connections.saturating_sub(1);
+                self.last_activity = Some(SystemTime::now());
+            }
+            Some(Event::SessionEnded) => self.connections = 0,
+            Some(Event::Error(error)) => {
+                if let Some(problem) = Problem::classify(&error) {
+                    self.problem = Some(problem);
+                }
+                self.last_error = Some(error);
+            }
+            _ => {}
+        }
+        self.stdout.push(line);
+    }
+
+    fn on_stderr(&mut self, line: String) {
+        if !line.trim().is_empty() {
+            self.last_error = Some(line.clone());
+        }
+        if let Some(problem) = Problem::classify(&line) {
+            self.problem = Some(problem);
+        }
+        self.stderr.push(line);
+    }
+
+    /// Reads whatever the child wrote on its way out, since that's usually why it went. Anything
+    /// still holding the pipes open only gets a moment.
+    async fn drain(&mut self) {
+        let SessionStatus::Running(spawned) = &mut self.status else {
+            return;
+        };
+        let Spawned { stdout, stderr, .. } = &mut **spawned;
+        let (mut out, mut err) = (vec![], vec![]);
+        let read = async {
+            let mut stdout = stdout.lines();
+            let mut stderr = stderr.lines();
+            futures::join!(
+                async {
+                    while let Ok(Some(line)) = stdout.next_line().await {
+                        out.push(line);
+                    }
+                },
+                async {
+                    while let Ok(Some(line)) = stderr.next_line().await {
+                        err.push(line);
+                    }
+                },
+            );
+        };
+        let _ = tokio::time::timeout(Duration::from_millis(200), read).await;
+        for line in out {
+            self.on_stdout(line);
+        }
+        for line in err {
+            self.on_stderr(line);
+        }
+    }
+
+    fn kill(&mut self) {
+        self.kill_deadline = None;
+        let res = match &mut self.status {
+            SessionStatus::Running(spawned) => process::kill(&mut spawned.child),
+            SessionStatus::Adopted(foreign) => foreign.kill(),
+            SessionStatus::Fresh => Ok(()),
+        };
+        if let Err(err) = res {
+            self.last_error = Some(err.to_string());
         }
     }
 
@@ -78,59 +756,47 @@ impl SessionActor {
         match msg {
             SessionMessage::Stop => self.terminate(),
             SessionMessage::Start => {
-                let mut command = Command::new("aws");
-                command.args([
-                    "ssm",
-                    "start-session",
-                    "--target",
-                    &self.target,
-                    "--document-name",
-                    "AWS-StartPortForwardingSession",
-                    "--parameters",
-                    &format!(
-                        "portNumber={},localPortNumber={}",
-                        self.dest_port, self.host_port
-                    ),
-                ]);
-                command.env("AWS_PROFILE", &self.env);
-                command.stdout(std::process::Stdio::piped());
-                command.stderr(std::process::Stdio::piped());
-                let res = command.spawn();
-                match res {
-                    Ok(mut child) => {
-                        let stdout = BufReader::new(child.stdout.take().unwrap());
-                        let stderr = BufReader::new(child.stderr.take().unwrap());
-                        self.status = SessionStatus::Running(child, stdout, stderr);
-                    }
-                    Err(err) => {
-                        self.status = SessionStatus::Stopped(Err(err));
-                    }
+                if matches!(self.status, SessionStatus::Fresh) && self.shutdown.is_empty() {
+                    self.restart = None;
+                    self.retries = 0;
+                    self.last_error = None;
+                    self.problem = None;
+                    self.local_port = None;
+                    self.spawn();
                 }
             }
-            SessionMessage::Healthy(reply) => {
-                let res = match &self.status {
-                    SessionStatus::Fresh | SessionStatus::Stopped(_) => false,
-                    SessionStatus::Running(child, _, _) => true,
-                };
-                reply.send(res).unwrap();
-            }
             SessionMessage::Stdout(reply) => {
                 reply.send(self.stdout.clone()).unwrap();
             }
             SessionMessage::Stderr(reply) => {
                 reply.send(self.stderr.clone()).unwrap();
             }
-            SessionMessage::UpdateDetails {
-                target,
-                env,
-                host_port,
-                dest_port,
-            } => {
+            SessionMessage::UpdateDetails(server) => {
+                self.terminate();
+                self.server = *server;
+                self.local_port = None;
+                self.credentials = None;
+                self.target = None;
+            }
+            SessionMessage::Adopt(foreign, port) => {
+                if matches!(self.status, SessionStatus::Fresh) && self.shutdown.is_empty() {
+                    self.restart = None;
+                    self.retries = 0;
+                    self.pgid = foreign.group.then_some(foreign.pid);
+                    self.local_port = Some(port);
+                    // Whatever it was started against, there's nothing to fail over to.
+                    self.candidates.clear();
+                    self.target = None;
+                    Record::new(foreign, &self.effective()).save();
+                    self.status = SessionStatus::Adopted(foreign);
+                    self.phase = Phase::Starting;
+                    self.started_at = Instant::now();
+                    self.schedule_probe(Duration::ZERO);
+                }
+            }
+            SessionMessage::Shutdown(reply) => {
+                self.shutdown.push(reply);
                 self.terminate();
-                self.target = target;
-                self.env = env;
-                self.host_port = host_port;
-                self.dest_port = dest_port;
             }
         }
     }
@@ -142,21 +808,102 @@ async fn run(mut actor: SessionActor) {
         // using an enum, but now this has become a right proper clusterfuck. This is necessary to lift out the futures
         // stuck inside the enum, we basically make one that instantly closes if it's not ready.
         let (child_fut, mut stdout_lines, mut stderr_lines) = match &mut actor.status {
-            SessionStatus::Running(child, stdout, stderr) => (
-                Either::Left(async move { child.wait().await }),
-                Some(stdout.lines()),
-                Some(stderr.lines()),
-            ),
+            SessionStatus::Running(spawned) => {
+                let Spawned {
+                    child,
+                    stdout,
+                    stderr,
+                } = &mut **spawned;
+                (
+                    Either::Left(Either::Left(async move { child.wait().await.map(Some) })),
+                    Some(stdout.lines()),
+                    Some(stderr.lines()),
+                )
+            }
+            SessionStatus::Adopted(foreign) => {
+                let exited = foreign.exited();
+                (
+                    Either::Left(Either::Right(async move {
+                        exited.await;
+                        Ok(None)
+                    })),
+                    None,
+                    None,
+                )
+            }
             _ => (Either::Right(futures::future::pending()), None, None),
         };
+        let probe_fut = match actor.probe.as_mut() {
+            Some(probe) => Either::Left(probe),
+            None => Either::Right(futures::future::pending()),
+        };
+        let restart_fut = match actor.restart.as_mut() {
+            Some(restart) => Either::Left(restart),
+            None => Either::Right(futures::future::pending()),
+        };
+
+        let kill_fut = match actor.kill_deadline.as_mut() {
+            Some(deadline) => Either::Left(deadline),
+            None => Either::Right(futures::future::pending()),
+        };
+        let assume_fut = match actor.assume.as_mut() {
+            Some(assume) => Either::Left(assume),
+            None => Either::Right(futures::future::pending()),
+        };
+        let resolve_fut = match actor.resolve.as_mut() {
+            Some(resolve) => Either::Left(resolve),
+            None => Either::Right(futures::future::pending()),
+        };
+        let closing_fut = match actor.closing.as_mut() {
+            Some(closing) => Either::Left(closing),
+            None => Either::Right(futures::future::pending()),
+        };
 
         tokio::select! {
-            Some(msg) = actor.reciever.recv() => {
-                actor.handle_message(msg);
+            msg = actor.reciever.recv() => match msg {
+                Some(msg) => actor.handle_message(msg),
+                // Every handle is gone, so nobody could ever stop this tunnel. Take it down now.
+                None => {
+                    actor.kill();
+                    if let SessionStatus::Adopted(foreign) = &actor.status {
+                        Record::forget(foreign.pid);
+                    }
+                    if let Some(pgid) = actor.pgid {
+                        process::reap_group(pgid);
+                        Record::forget(pgid);
+                    }
+                    break;
+                }
+            },
+
+            _ = kill_fut => {
+                actor.kill();
             }
 
             status = child_fut => {
-                actor.status = SessionStatus::Stopped(status)
+                actor.drain().await;
+                actor.on_exit(status);
+            }
+
+            _ = restart_fut => {
+                actor.restart = None;
+                actor.spawn();
+            }
+
+            ok = probe_fut => {
+                actor.on_probe(ok);
+            }
+
+            res = assume_fut => {
+                actor.on_assumed(res);
+            }
+
+            res = resolve_fut => {
+                actor.on_resolved(res);
+            }
+
+            res = closing_fut => {
+                actor.on_closed(res);
             }
 
             line = async {
@@ -167,7 +914,7 @@ async fn run(mut actor: SessionActor) {
                 }
             } => {
                 if let Ok(Some(line)) = line {
-                    actor.stdout.push(line);
+                    actor.on_stdout(line);
                 }
             }
 
@@ -179,25 +926,117 @@ async fn run(mut actor: SessionActor) {
                 }
             } => {
                 if let Ok(Some(line)) = line {
-                    actor.stderr.push(line);
+                    actor.on_stderr(line);
                 }
             }
         }
+
+        actor.publish();
+
+        if !actor.shutdown.is_empty()
+            && matches!(actor.status, SessionStatus::Fresh)
+            && actor.closing.is_none()
+        {
+            for reply in actor.shutdown.drain(..) {
+                let _ = reply.send(());
+            }
+            break;
+        }
+    }
+}
+
+/// Stands in for an actor when the real one lives in a daemon, forwarding messages over the
+/// control socket. State arrives separately, through the `Remote` subscription.
+async fn run_remote(
+    mut reciever: mpsc::Receiver<SessionMessage>,
+    client: control::Client,
+    id: SessionId,
+) {
+    while let Some(msg) = reciever.recv().await {
+        // Failures show up as the state going stale, and the subscription reports a lost daemon.
+        match msg {
+            SessionMessage::Start => {
+                let _ = client.request(&Request::Start { id }).await;
+            }
+            SessionMessage::Stop => {
+                let _ = client.request(&Request::Stop { id }).await;
+            }
+            SessionMessage::Stdout(reply) => {
+                let stdout = match client.request(&Request::Logs { id }).await {
+                    Ok(Response::Logs { stdout, .. }) => stdout,
+                    _ => vec![],
+                };
+                let _ = reply.send(stdout);
+            }
+            SessionMessage::Stderr(reply) => {
+                let stderr = match client.request(&Request::Logs { id }).await {
+                    Ok(Response::Logs { stderr, .. }) => stderr,
+                    _ => vec![],
+                };
+                let _ = reply.send(stderr);
+            }
+            SessionMessage::UpdateDetails(server) => {
+                let _ = client
+                    .request(&Request::Update {
+                        id,
+                        server: *server,
+                    })
+                    .await;
+            }
+            // The daemon deals with its own leftovers.
+            SessionMessage::Adopt(..) => {}
+            // Detaching: the whole point is that the tunnel outlives us.
+            SessionMessage::Shutdown(reply) => {
+                let _ = reply.send(());
+                return;
+            }
+        }
     }
+    // Every handle was dropped without detaching, which is how a local session gets deleted.
+    let _ = client.request(&Request::Remove { id }).await;
 }
 
 #[derive(Clone)]
 pub struct Session {
     sender: mpsc::Sender<SessionMessage>,
+    state: watch::Receiver<SessionState>,
 }
 
 impl Session {
-    pub fn new(target: String, env: String, host_port: usize, dest_port: usize) -> Self {
+    pub fn new(server: Server, defaults: Defaults) -> Self {
         let (sender, receiver) = mpsc::channel(8);
-        let actor = SessionActor::new(receiver, target, env, host_port, dest_port);
+        let (publisher, state) = watch::channel(SessionState::default());
+        let actor = SessionActor::new(receiver, publisher, server, defaults);
         tokio::spawn(run(actor));
 
-        Self { sender }
+        Self { sender, state }
+    }
+
+    /// A session whose actor lives in a daemon; see `run_remote`.
+    pub fn remote(
+        client: control::Client,
+        id: SessionId,
+        state: watch::Receiver<SessionState>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(8);
+        tokio::spawn(run_remote(receiver, client, id));
+
+        Self { sender, state }
+    }
+
+    /// A receiver of its own, for watching the session without keeping the actor alive.
+    pub fn watch(&self) -> watch::Receiver<SessionState> {
+        self.state.clone()
+    }
+
+    /// The most recently published state, without waiting on the actor.
+    pub fn state(&self) -> SessionState {
+        self.state.borrow().clone()
+    }
+
+    /// Resolves once the actor publishes a state this handle hasn't seen yet.
+    pub async fn changed(&mut self) -> Result<(), watch::error::RecvError> {
+        self.state.changed().await
     }
 
     pub async fn start(&self) {
@@ -214,13 +1053,6 @@ impl Session {
             .expect("Actor dead?");
     }
 
-    pub async fn healthy(&self) -> bool {
-        let (send, recv) = oneshot::channel();
-        let msg = SessionMessage::Healthy(send);
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor killed?")
-    }
-
     pub async fn stdout(&self) -> Vec<String> {
         let (send, recv) = oneshot::channel();
         let msg = SessionMessage::Stdout(send);
@@ -228,6 +1060,7 @@ impl Session {
         recv.await.expect("Actor killed?")
     }
 
+    #[allow(unused)]
     pub async fn stderr(&self) -> Vec<String> {
         let (send, recv) = oneshot::channel();
         let msg = SessionMessage::Stderr(send);
@@ -235,13 +1068,29 @@ impl Session {
         recv.await.expect("Actor killed?")
     }
 
-    pub async fn update(&self, target: String, env: String, host_port: usize, dest_port: usize) {
-        let msg = SessionMessage::UpdateDetails {
-            target,
-            env,
-            host_port,
-            dest_port,
-        };
+    pub async fn adopt(&self, foreign: Foreign, port: usize) {
+        self.sender
+            .send(SessionMessage::Adopt(foreign, port))
+            .await
+            .expect("Actor dead?");
+    }
+
+    /// Stops the session and waits for its child to be gone. The actor exits afterwards, so
+    /// this handle is useless once it returns.
+    pub async fn shutdown(&self) {
+        let (send, recv) = oneshot::channel();
+        if self
+            .sender
+            .send(SessionMessage::Shutdown(send))
+            .await
+            .is_ok()
+        {
+            let _ = recv.await;
+        }
+    }
+
+    pub async fn update(&self, server: Server) {
+        let msg = SessionMessage::UpdateDetails(Box::new(server));
         self.sender.send(msg).await.expect("Actor ded?");
     }
 }
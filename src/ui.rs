@@ -1,29 +1,506 @@
 use anyhow::Result;
-use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind};
-use futures::{FutureExt, StreamExt};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::{
+    future::{BoxFuture, Either},
+    StreamExt,
+};
 use ratatui::{
     layout::{Constraint, Direction, Layout, Margin, Rect},
     style::{Color, Style, Stylize},
-    widgets::{Block, BorderType, Borders, Cell, Paragraph, Row, Table, TableState},
+    text::Line,
+    widgets::{Block, BorderType, Borders, Cell, Clear, Paragraph, Row, Table, TableState, Wrap},
     DefaultTerminal, Frame,
 };
-use std::{borrow::Cow, path::PathBuf, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::{mpsc, Mutex};
 
-use crate::{servers::Server, ssm::Session, Uhh};
+use crate::{
+    aws::{self, Problem},
+    control::Remote,
+    orphans::{Orphan, Record, Source},
+    process,
+    servers::{Defaults, Server},
+    ssm::{self, Phase, Session, SessionState},
+    Uhh,
+};
 
-pub async fn run(server_list: Vec<Uhh>, connections_file: PathBuf) -> Result<()> {
+pub async fn run(
+    server_list: Vec<Uhh>,
+    connections_file: PathBuf,
+    defaults: Defaults,
+    orphans: Vec<Orphan>,
+    remote: Option<Remote>,
+) -> Result<()> {
     let terminal = ratatui::init();
-    App::new(server_list, connections_file)
-        .run(terminal)
-        .await?;
+    let mut app = App::new(server_list, connections_file, defaults);
+    app.remote = remote;
+    if !orphans.is_empty() {
+        app.mode = Mode::Orphans(OrphanView::new(orphans));
+    }
+    let res = app.run(terminal).await;
     ratatui::restore();
 
-    Ok(())
+    let active = app
+        .server_list
+        .iter()
+        .filter(|(_, _, state)| state.phase.is_active())
+        .count();
+    if active > 0 && app.remote.is_none() {
+        eprintln!("Stopping {} session(s)...", active);
+    }
+    // When attached to a daemon this only detaches, leaving the tunnels running.
+    process::stop_all(app.server_list.into_iter().map(|(session, _, _)| session)).await;
+
+    res
 }
 
 enum Mode {
     Main,
     Edit(EditView),
+    Orphans(OrphanView),
+    /// Offering to log in again for a profile whose credentials have expired.
+    Login(String),
+    Import(ImportView),
+    Sessions(Box<SessionsView>),
+}
+
+/// Tunnels left running by an earlier run, offered up for adoption or killing before anything
+/// else happens.
+struct OrphanView {
+    orphans: Vec<Orphan>,
+    table_state: TableState,
+}
+
+impl OrphanView {
+    fn new(orphans: Vec<Orphan>) -> Self {
+        let mut table_state = TableState::default();
+        table_state.select_first();
+        Self {
+            orphans,
+            table_state,
+        }
+    }
+
+    fn draw(&mut self, f: &mut Frame, area: Rect, server_list: &[Uhh]) {
+        let block = Block::default()
+            .title("Leftover tunnels from a previous run")
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded);
+
+        let rows = self.orphans.iter().map(|o| {
+            let server = o
+                .server
+                .and_then(|i| server_list.get(i))
+                .map(|(_, server, _)| server.name.as_str())
+                .unwrap_or("?");
+            Row::new(vec![
+                Cell::from(o.record.pid.to_string()),
+                Cell::from(o.process.clone().unwrap_or_else(|| "?".into())),
+                Cell::from(o.record.host_port.to_string()),
+                Cell::from(server.to_string()),
+                Cell::from(match o.source {
+                    Source::Recorded if o.verified => "Recorded",
+                    Source::Recorded => "Recorded?",
+                    Source::Listening => "Listening",
+                }),
+            ])
+        });
+
+        let table = Table::new(rows, vec![10, 20, 10, 30, 10])
+            .block(block)
+            .header(
+                Row::new(vec![
+                    Cell::from("PID"),
+                    Cell::from("Process"),
+                    Cell::from("Port"),
+                    Cell::from("Server"),
+                    Cell::from("Found by"),
+                ])
+                .style(Style::new().bold().bg(Color::LightRed)),
+            )
+            .highlight_symbol(" 👉 ")
+            .row_highlight_style(Style::new().light_green());
+
+        f.render_stateful_widget(table, area, &mut self.table_state);
+    }
+
+    /// Takes the selected orphan off the list, if there is one.
+    fn take_selected(&mut self) -> Option<Orphan> {
+        let sel = self.table_state.selected()?;
+        if sel >= self.orphans.len() {
+            return None;
+        }
+        Some(self.orphans.remove(sel))
+    }
+}
+
+/// Where an import has got to.
+enum Listing {
+    /// Typing in the profile to look in.
+    Profile,
+    Loading,
+    Loaded(Vec<aws::ManagedInstance>),
+    Failed(String),
+}
+
+/// Picking instances out of a profile's SSM inventory to add as servers.
+struct ImportView {
+    profile: String,
+    listing: Listing,
+    /// Indexes of the instances ticked for import.
+    chosen: HashSet<usize>,
+    /// Instance IDs already configured for the profile, which are shown but not offered.
+    existing: HashSet<String>,
+    table_state: TableState,
+}
+
+impl ImportView {
+    fn new(profile: String) -> Self {
+        Self {
+            profile,
+            listing: Listing::Profile,
+            chosen: HashSet::new(),
+            existing: HashSet::new(),
+            table_state: TableState::default(),
+        }
+    }
+
+    /// Takes the listing, ticking everything online that isn't configured yet.
+    fn loaded(&mut self, instances: Vec<aws::ManagedInstance>) {
+        self.chosen = instances
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| i.ping_status == "Online" && !self.existing.contains(&i.instance_id))
+            .map(|(index, _)| index)
+            .collect();
+        self.table_state.select_first();
+        self.listing = Listing::Loaded(instances);
+    }
+
+    fn toggle_selected(&mut self) {
+        let (Some(sel), Listing::Loaded(instances)) = (self.table_state.selected(), &self.listing)
+        else {
+            return;
+        };
+        let Some(instance) = instances.get(sel) else {
+            return;
+        };
+        if !self.chosen.remove(&sel) && !self.existing.contains(&instance.instance_id) {
+            self.chosen.insert(sel);
+        }
+    }
+
+    /// New servers for the ticked instances.
+    fn servers(&self) -> Vec<Server> {
+        let Listing::Loaded(instances) = &self.listing else {
+            return vec![];
+        };
+        let mut chosen: Vec<_> = self.chosen.iter().copied().collect();
+        chosen.sort();
+        chosen
+            .into_iter()
+            .filter_map(|i| instances.get(i))
+            .map(|instance| Server {
+                name: instance
+                    .name
+                    .clone()
+                    .or_else(|| instance.computer_name.clone())
+                    .unwrap_or_else(|| instance.instance_id.clone()),
+                identifier: instance.instance_id.clone(),
+                env: self.profile.clone(),
+                host_port: 0,
+                // Every instance has SSH, more or less. Edit it to whatever it's really for.
+                dest_port: 22,
+                ..Server::default()
+            })
+            .collect()
+    }
+
+    fn draw(&mut self, f: &mut Frame, area: Rect) {
+        let block = Block::default()
+            .title(format!("Import instances from {}", self.profile))
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded);
+
+        let instances = match &self.listing {
+            Listing::Profile => {
+                let prompt = Paragraph::new(format!("Profile: {}", self.profile)).block(block);
+                f.render_widget(prompt, area);
+                return;
+            }
+            Listing::Loading => {
+                let waiting = Paragraph::new("Asking SSM for managed instances...").block(block);
+                f.render_widget(waiting, area);
+                return;
+            }
+            Listing::Failed(error) => {
+                let error = Paragraph::new(error.as_str())
+                    .red()
+                    .wrap(Wrap { trim: true })
+                    .block(block);
+                f.render_widget(error, area);
+                return;
+            }
+            Listing::Loaded(instances) => instances,
+        };
+
+        let rows = instances.iter().enumerate().map(|(i, instance)| {
+            let tick = if self.existing.contains(&instance.instance_id) {
+                "added"
+            } else if self.chosen.contains(&i) {
+                "[x]"
+            } else {
+                "[ ]"
+            };
+            let ping = Cell::from(instance.ping_status.as_str());
+            Row::new(vec![
+                Cell::from(tick),
+                Cell::from(instance.instance_id.as_str()),
+                Cell::from(instance.name.as_deref().unwrap_or("")),
+                if instance.ping_status == "Online" {
+                    ping.green()
+                } else {
+                    ping.red()
+                },
+                Cell::from(instance.platform_name.as_deref().unwrap_or("")),
+                Cell::from(instance.ip_address.as_deref().unwrap_or("")),
+            ])
+        });
+
+        let widths = [
+            Constraint::Length(6),
+            Constraint::Length(22),
+            Constraint::Length(30),
+            Constraint::Length(16),
+            Constraint::Length(24),
+            Constraint::Fill(1),
+        ];
+        let table = Table::new(rows, widths)
+            .block(block)
+            .header(
+                Row::new(vec![
+                    Cell::from(""),
+                    Cell::from("Instance"),
+                    Cell::from("Name"),
+                    Cell::from("Ping"),
+                    Cell::from("Platform"),
+                    Cell::from("IP Address"),
+                ])
+                .style(Style::new().bold().bg(Color::LightRed)),
+            )
+            .highlight_symbol(" 👉 ")
+            .row_highlight_style(Style::new().light_green());
+
+        f.render_stateful_widget(table, area, &mut self.table_state);
+    }
+}
+
+/// A role's credentials and when they run out, kept so that aws calls made for the UI don't
+/// assume the role every time. Clones share the same credentials.
+#[derive(Clone, Default)]
+struct RoleCredentials(Arc<Mutex<Option<(aws::Credentials, Instant)>>>);
+
+impl RoleCredentials {
+    /// Credentials for the server's role, or `None` if it doesn't have one.
+    async fn get(&self, server: &Server) -> Result<Option<aws::Credentials>> {
+        let mut cached = self.0.lock().await;
+        if let Some((credentials, expires)) = &*cached {
+            if *expires > Instant::now() + ssm::REFRESH_MARGIN {
+                return Ok(Some(credentials.clone()));
+            }
+        }
+        let credentials = aws::credentials_for(server).await?;
+        if let Some(credentials) = &credentials {
+            *cached = Some((credentials.clone(), Instant::now() + aws::ROLE_DURATION));
+        }
+        Ok(credentials)
+    }
+}
+
+/// A target's sessions, as listed for the sessions view.
+type SessionList = (String, Result<Vec<aws::RemoteSession>>);
+
+/// The SSM sessions open against a server's target, including ones left behind by clients that
+/// died without closing them.
+struct SessionsView {
+    /// With defaults applied, for running aws as.
+    server: Server,
+    target: String,
+    /// The session our own tunnel holds, which is better stopped the usual way.
+    own: Option<String>,
+    /// `None` while asking.
+    sessions: Option<Result<Vec<aws::RemoteSession>, String>>,
+    /// Session IDs ticked for terminating.
+    chosen: HashSet<String>,
+    credentials: RoleCredentials,
+    table_state: TableState,
+}
+
+impl SessionsView {
+    fn new(server: Server, target: String, own: Option<String>) -> Self {
+        Self {
+            server,
+            target,
+            own,
+            sessions: None,
+            chosen: HashSet::new(),
+            credentials: RoleCredentials::default(),
+            table_state: TableState::default(),
+        }
+    }
+
+    /// Asks for the sessions afresh.
+    fn list(&mut self, results: &mpsc::UnboundedSender<SessionList>) {
+        self.sessions = None;
+        let (server, target) = (self.server.clone(), self.target.clone());
+        let credentials = self.credentials.clone();
+        let results = results.clone();
+        tokio::spawn(async move {
+            let sessions = async {
+                let credentials = credentials.get(&server).await?;
+                aws::active_sessions(&server, &target, credentials.as_ref()).await
+            };
+            let _ = results.send((target.clone(), sessions.await));
+        });
+    }
+
+    /// Terminates some of the sessions, then lists what's left.
+    fn terminate(&mut self, results: &mpsc::UnboundedSender<SessionList>, ids: Vec<String>) {
+        self.sessions = None;
+        let (server, target) = (self.server.clone(), self.target.clone());
+        let credentials = self.credentials.clone();
+        let results = results.clone();
+        tokio::spawn(async move {
+            let sessions = async {
+                let credentials = credentials.get(&server).await?;
+                for id in ids {
+                    aws::terminate_session(&server, &id, credentials.as_ref()).await?;
+                }
+                aws::active_sessions(&server, &target, credentials.as_ref()).await
+            };
+            let _ = results.send((target.clone(), sessions.await));
+        });
+    }
+
+    fn loaded(&mut self, sessions: Result<Vec<aws::RemoteSession>>) {
+        self.sessions = Some(sessions.map_err(|err| err.to_string()));
+        self.chosen.clear();
+        self.table_state.select_first();
+    }
+
+    fn selected(&self) -> Option<&aws::RemoteSession> {
+        match &self.sessions {
+            Some(Ok(sessions)) => sessions.get(self.table_state.selected()?),
+            _ => None,
+        }
+    }
+
+    /// Our own session isn't offered: terminating it here would only have the tunnel restart.
+    fn toggle_selected(&mut self) {
+        let Some(id) = self
+            .selected()
+            .map(|s| s.session_id.clone())
+            .filter(|id| self.own.as_ref() != Some(id))
+        else {
+            return;
+        };
+        if !self.chosen.remove(&id) {
+            self.chosen.insert(id);
+        }
+    }
+
+    /// The ticked sessions, or the highlighted one if none are, never counting our own.
+    fn to_terminate(&self) -> Vec<String> {
+        let ids = if self.chosen.is_empty() {
+            self.selected()
+                .map(|s| vec![s.session_id.clone()])
+                .unwrap_or_default()
+        } else {
+            self.chosen.iter().cloned().collect()
+        };
+        ids.into_iter()
+            .filter(|id| self.own.as_ref() != Some(id))
+            .collect()
+    }
+
+    fn draw(&mut self, f: &mut Frame, area: Rect) {
+        let block = Block::default()
+            .title(format!(
+                "Active SSM sessions on {} ({})",
+                self.target, self.server.env
+            ))
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded);
+
+        let sessions = match &self.sessions {
+            None => {
+                let waiting = Paragraph::new("Asking SSM for sessions...").block(block);
+                f.render_widget(waiting, area);
+                return;
+            }
+            Some(Err(error)) => {
+                let error = Paragraph::new(error.as_str())
+                    .red()
+                    .wrap(Wrap { trim: true })
+                    .block(block);
+                f.render_widget(error, area);
+                return;
+            }
+            Some(Ok(sessions)) if sessions.is_empty() => {
+                let none = Paragraph::new("No active sessions").block(block);
+                f.render_widget(none, area);
+                return;
+            }
+            Some(Ok(sessions)) => sessions,
+        };
+
+        let rows = sessions.iter().map(|session| {
+            let tick = if self.own.as_ref() == Some(&session.session_id) {
+                "ours"
+            } else if self.chosen.contains(&session.session_id) {
+                "[x]"
+            } else {
+                "[ ]"
+            };
+            Row::new(vec![
+                Cell::from(tick),
+                Cell::from(session.session_id.as_str()),
+                Cell::from(session.owner.as_deref().unwrap_or("")),
+                Cell::from(session.started()),
+                Cell::from(session.document_name.as_deref().unwrap_or("")),
+            ])
+        });
+
+        let widths = [
+            Constraint::Length(6),
+            Constraint::Length(40),
+            Constraint::Length(50),
+            Constraint::Length(34),
+            Constraint::Fill(1),
+        ];
+        let table = Table::new(rows, widths)
+            .block(block)
+            .header(
+                Row::new(vec![
+                    Cell::from(""),
+                    Cell::from("Session"),
+                    Cell::from("Owner"),
+                    Cell::from("Started"),
+                    Cell::from("Document"),
+                ])
+                .style(Style::new().bold().bg(Color::LightRed)),
+            )
+            .highlight_symbol(" 👉 ")
+            .row_highlight_style(Style::new().light_green());
+
+        f.render_stateful_widget(table, area, &mut self.table_state);
+    }
 }
 
 struct EditView {
@@ -31,28 +508,55 @@ struct EditView {
     stdout: Vec<String>,
     scroll: usize,
     session: Session,
-    server: Server,
     form_fields: Vec<String>,
     active_field: usize,
+    /// Names and source ports of every other server, to warn about collisions.
+    other_ports: Vec<(String, usize)>,
 }
 
 impl EditView {
-    fn new(selected: usize, session: Session, server: Server) -> Self {
+    fn new(selected: usize, session: Session, server: Server, server_list: &[Uhh]) -> Self {
+        let other_ports = server_list
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != selected)
+            .map(|(_, (_, s, _))| (s.name.clone(), s.host_port))
+            .collect();
         let form_fields = vec![
             server.identifier.clone(),
             server.name.clone(),
             server.env.clone(),
-            server.host_port.to_string(),
+            port_field(server.host_port),
             server.dest_port.to_string(),
+            server.remote_host.clone().unwrap_or_default(),
         ];
         Self {
             selected,
             stdout: Vec::new(),
             scroll: 0,
             session,
-            server,
             form_fields,
             active_field: 0,
+            other_ports,
+        }
+    }
+
+    fn port_warning(&self) -> Option<String> {
+        let port = parse_port_field(&self.form_fields[3]).filter(|port| *port != 0)?;
+        let clashes: Vec<_> = self
+            .other_ports
+            .iter()
+            .filter(|(_, p)| *p == port)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if clashes.is_empty() {
+            None
+        } else {
+            Some(format!(
+                " Source port {} is also used by: {} ",
+                port,
+                clashes.join(", ")
+            ))
         }
     }
 
@@ -100,10 +604,13 @@ impl EditView {
             .constraints([Constraint::Fill(1), Constraint::Fill(1)])
             .split(area);
 
-        let form_block = Block::default()
+        let mut form_block = Block::default()
             .title("Edit Server")
             .borders(Borders::ALL)
             .border_type(BorderType::Rounded);
+        if let Some(warning) = self.port_warning() {
+            form_block = form_block.title_bottom(Line::from(warning).red().bold());
+        }
         f.render_widget(form_block, chunks[0]);
 
         let form_fields = [
@@ -112,6 +619,7 @@ impl EditView {
             ("Environment", &self.form_fields[2]),
             ("Source Port", &self.form_fields[3]),
             ("Destination Port", &self.form_fields[4]),
+            ("Remote Host", &self.form_fields[5]),
         ];
 
         let form_items: Vec<Paragraph> = form_fields
@@ -139,8 +647,12 @@ impl EditView {
         }
 
         // Output block
+        let title = match self.session.state().session_id {
+            Some(id) => format!("SSM Output (session {})", id),
+            None => "SSM Output".to_string(),
+        };
         let output_block = Block::default()
-            .title("SSM Output")
+            .title(title)
             .borders(Borders::ALL)
             .border_type(BorderType::Rounded);
         f.render_widget(output_block, chunks[1]);
@@ -162,39 +674,259 @@ impl EditView {
     }
 }
 
+/// A box of at most the given size in the middle of `area`.
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    }
+}
+
+/// Who a server's aws commands run as: its profile, and the role it assumes with it, if any.
+type Principal = (String, Option<String>);
+
+fn principal(server: &Server) -> Principal {
+    (server.env.clone(), server.role_arn.clone())
+}
+
+/// What STS makes of a profile, or of the role a server assumes with it.
+enum Whoami {
+    Checking,
+    Known(aws::Identity),
+    Failed(String),
+}
+
+/// How long ago something happened, roughly.
+fn ago(time: SystemTime) -> String {
+    let secs = time.elapsed().unwrap_or_default().as_secs();
+    match secs {
+        0..60 => format!("{}s ago", secs),
+        60..3600 => format!("{}m ago", secs / 60),
+        _ => format!("{}h ago", secs / 3600),
+    }
+}
+
+/// How long until `ago` would say something different about the same time.
+fn ago_changes(time: SystemTime) -> Duration {
+    let elapsed = time.elapsed().unwrap_or_default();
+    let unit = match elapsed.as_secs() {
+        0..60 => 1,
+        60..3600 => 60,
+        _ => 3600,
+    };
+    let next = Duration::from_secs((elapsed.as_secs() / unit + 1) * unit);
+    next.saturating_sub(elapsed)
+}
+
+/// Source ports are edited as text, where `0` reads better as "auto".
+fn port_field(port: usize) -> String {
+    match port {
+        0 => "auto".to_string(),
+        port => port.to_string(),
+    }
+}
+
+fn parse_port_field(field: &str) -> Option<usize> {
+    let field = field.trim();
+    if field.is_empty() || field.eq_ignore_ascii_case("auto") {
+        Some(0)
+    } else {
+        field.parse().ok()
+    }
+}
+
+/// Names of the other servers configured with the same source port as the one at `index`.
+fn port_sharers(server_list: &[Uhh], index: usize) -> Vec<&str> {
+    let port = server_list[index].1.host_port;
+    if port == 0 {
+        return vec![];
+    }
+    server_list
+        .iter()
+        .enumerate()
+        .filter(|(i, (_, server, _))| *i != index && server.host_port == port)
+        .map(|(_, (_, server, _))| server.name.as_str())
+        .collect()
+}
+
 pub struct App {
     mode: Mode,
     server_list: Vec<Uhh>,
     table_state: TableState,
     running: bool,
-    event_stream: EventStream,
+    /// Only `None` while something else has the terminal, so it doesn't eat their keystrokes.
+    event_stream: Option<EventStream>,
     connections_file: PathBuf,
+    defaults: Defaults,
+    shutdown_signal: BoxFuture<'static, ()>,
+    /// Set when attached to a daemon, which then owns the sessions and the connections file.
+    remote: Option<Remote>,
+    /// A profile to log in to once the terminal is free.
+    login: Option<String>,
+    /// Profiles already offered a login, so a declined offer doesn't keep popping up.
+    offered: HashSet<String>,
+    /// Something that went wrong outside any one session, shown instead of the help until the
+    /// next key.
+    notice: Option<String>,
+    /// Identity checks by profile and role, so each is only run once.
+    identities: HashMap<Principal, Whoami>,
+    identity_tx: mpsc::UnboundedSender<(Principal, Result<aws::Identity>)>,
+    identity_rx: mpsc::UnboundedReceiver<(Principal, Result<aws::Identity>)>,
+    import_tx: mpsc::UnboundedSender<(String, Result<Vec<aws::ManagedInstance>>)>,
+    import_rx: mpsc::UnboundedReceiver<(String, Result<Vec<aws::ManagedInstance>>)>,
+    sessions_tx: mpsc::UnboundedSender<SessionList>,
+    sessions_rx: mpsc::UnboundedReceiver<SessionList>,
 }
 
 impl App {
-    fn new(server_list: Vec<Uhh>, connections_file: PathBuf) -> Self {
+    fn new(server_list: Vec<Uhh>, connections_file: PathBuf, defaults: Defaults) -> Self {
+        let (identity_tx, identity_rx) = mpsc::unbounded_channel();
+        let (import_tx, import_rx) = mpsc::unbounded_channel();
+        let (sessions_tx, sessions_rx) = mpsc::unbounded_channel();
         let mut res = App {
             mode: Mode::Main,
             server_list,
             table_state: TableState::default(),
-            event_stream: EventStream::default(),
+            event_stream: Some(EventStream::default()),
             running: false,
             connections_file,
+            defaults,
+            shutdown_signal: Box::pin(process::shutdown_signal()),
+            remote: None,
+            login: None,
+            offered: HashSet::new(),
+            notice: None,
+            identities: HashMap::new(),
+            identity_tx,
+            identity_rx,
+            import_tx,
+            import_rx,
+            sessions_tx,
+            sessions_rx,
         };
         res.table_state.select_first();
+        res.check_identities();
 
         res
     }
 
-    pub async fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
+    pub async fn run(&mut self, mut terminal: DefaultTerminal) -> Result<()> {
         self.running = true;
         while self.running {
             terminal.draw(|f| self.draw(f))?;
             self.handle_events().await?;
+            if let Some(profile) = self.login.take() {
+                let Some(server) = self.profile_server(&profile) else {
+                    continue;
+                };
+                // The login wants the terminal for its prompts, so hand it over for the duration.
+                self.event_stream = None;
+                ratatui::restore();
+                let status = aws::sso_login(&server).await;
+                terminal = ratatui::init();
+                self.event_stream = Some(EventStream::default());
+                if status.is_ok_and(|status| status.success()) {
+                    self.offered.remove(&profile);
+                    self.identities.retain(|(env, _), _| *env != profile);
+                    self.check_identities();
+                    self.retry_profile(&profile).await;
+                }
+            }
         }
         Ok(())
     }
 
+    /// A server using the profile, with defaults applied, for running aws commands as it would.
+    fn profile_server(&self, profile: &str) -> Option<Server> {
+        self.server_list
+            .iter()
+            .find(|(_, server, _)| server.env == profile)
+            .map(|(_, server, _)| server.with_defaults(&self.defaults))
+    }
+
+    /// Asks STS about every AWS profile in use that hasn't been asked about yet.
+    fn check_identities(&mut self) {
+        for (_, server, _) in &self.server_list {
+            let principal = principal(server);
+            if !server.backend.is_ssm() || self.identities.contains_key(&principal) {
+                continue;
+            }
+            self.identities.insert(principal.clone(), Whoami::Checking);
+            let server = server.with_defaults(&self.defaults);
+            let results = self.identity_tx.clone();
+            tokio::spawn(async move {
+                let identity = async {
+                    let credentials = aws::credentials_for(&server).await?;
+                    aws::caller_identity(&server, credentials.as_ref()).await
+                };
+                let _ = results.send((principal, identity.await));
+            });
+        }
+    }
+
+    /// Writes the servers back to the connections file, or has the daemon write its own.
+    fn save(&self) {
+        if let Some(remote) = self.remote.clone() {
+            tokio::spawn(async move {
+                if let Err(e) = remote.save().await {
+                    eprintln!("Failed to save servers: {}", e);
+                }
+            });
+            return;
+        }
+        let servers: Vec<_> = self
+            .server_list
+            .iter()
+            .map(|(_, server, _)| server.clone())
+            .collect();
+        let path = self.connections_file.clone();
+        let defaults = self.defaults.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::servers::save(path, &defaults, &servers).await {
+                eprintln!("Failed to save servers: {}", e);
+            }
+        });
+    }
+
+    /// Adds a server to the list, and to the daemon when attached to one.
+    async fn add(&mut self, server: Server) -> Result<()> {
+        let session = match &self.remote {
+            Some(remote) => remote.add(server.clone()).await?,
+            None => Session::new(server.clone(), self.defaults.clone()),
+        };
+        self.server_list
+            .push((session, server, SessionState::default()));
+        Ok(())
+    }
+
+    /// Lists the managed instances a profile can see, for the import view.
+    fn list_instances(&self, profile: String) {
+        let server = Server {
+            env: profile,
+            ..Server::default()
+        }
+        .with_defaults(&self.defaults);
+        let results = self.import_tx.clone();
+        tokio::spawn(async move {
+            let instances = aws::managed_instances(&server).await;
+            let _ = results.send((server.env, instances));
+        });
+    }
+
+    /// Starts every session that fell over for want of a login to this profile.
+    async fn retry_profile(&self, profile: &str) {
+        for (session, server, state) in &self.server_list {
+            let expired = state.problem.as_ref().is_some_and(|p| p.needs_login());
+            if server.env == profile && expired && !state.phase.is_active() {
+                session.start().await;
+            }
+        }
+    }
+
     fn draw(&mut self, f: &mut Frame) {
         let cunks = Layout::default()
             .direction(Direction::Vertical)
@@ -202,29 +934,107 @@ impl App {
             .split(f.area());
 
         match &mut self.mode {
-            Mode::Main => {
-                let block = Block::default()
-                    .title("Servers")
+            Mode::Main | Mode::Login(_) => {
+                let title = if self.remote.is_some() {
+                    "Servers (attached to daemon)"
+                } else {
+                    "Servers"
+                };
+                let mut block = Block::default()
+                    .title(title)
                     .borders(Borders::ALL)
                     .border_type(BorderType::Rounded);
+                let selected = self
+                    .table_state
+                    .selected()
+                    .and_then(|sel| self.server_list.get(sel))
+                    .filter(|(_, server, _)| server.backend.is_ssm());
+                if let Some((_, server, _)) = selected {
+                    match self.identities.get(&principal(server)) {
+                        Some(Whoami::Known(identity)) => {
+                            block =
+                                block.title_bottom(format!(" {}: {} ", server.env, identity.arn));
+                        }
+                        Some(Whoami::Failed(error)) => {
+                            block = block.title_bottom(
+                                Line::from(format!(" {}: {} ", server.env, error)).red(),
+                            );
+                        }
+                        _ => {}
+                    }
+                }
 
-                let rows = self.server_list.iter().map(|s| {
+                let rows = self.server_list.iter().enumerate().map(|(i, s)| {
+                    let mut error = match (&s.2.problem, &s.2.last_error) {
+                        (Some(problem), Some(line)) => format!("{}: {}", problem, line),
+                        (Some(problem), None) => problem.to_string(),
+                        (None, line) => line.clone().unwrap_or_default(),
+                    };
+                    if matches!(s.2.phase, Phase::PortInUse { .. }) {
+                        let sharers = port_sharers(&self.server_list, i);
+                        if !sharers.is_empty() {
+                            error
+                                .push_str(&format!(", also configured for {}", sharers.join(", ")));
+                        }
+                    }
+                    let account = match self.identities.get(&principal(&s.1)) {
+                        _ if !s.1.backend.is_ssm() => Cell::from("-"),
+                        Some(Whoami::Known(identity)) => Cell::from(identity.account.clone()),
+                        Some(Whoami::Failed(_)) => Cell::from("unknown").style(Style::new().red()),
+                        Some(Whoami::Checking) | None => Cell::from("checking..."),
+                    };
+                    let port = match (s.1.host_port, s.2.local_port) {
+                        (0, Some(port)) => format!("{} (auto)", port),
+                        (port, _) => port_field(port),
+                    };
+                    // After a failover, or with a tag selector, the configured target isn't where
+                    // the tunnel actually goes.
+                    let identifier = match &s.2.target {
+                        Some(target) if *target != s.1.identifier => {
+                            Cow::Owned(format!("{} ({})", target, s.1.identifier))
+                        }
+                        _ => Cow::Borrowed(s.1.identifier.as_str()),
+                    };
                     Row::new(vec![
                         Cell::from(Cow::Borrowed(s.1.name.as_str())),
-                        Cell::from(Cow::Borrowed(s.1.identifier.as_str())),
+                        Cell::from(identifier),
                         Cell::from(Cow::Borrowed(s.1.env.as_str())),
-                        Cell::from(if s.2 { "Running" } else { "Stopped" }),
+                        account,
+                        Cell::from(port),
+                        Cell::from(s.2.label()),
+                        Cell::from(match s.2.phase {
+                            Phase::Ready | Phase::Unhealthy => s.2.connections.to_string(),
+                            _ => "-".to_string(),
+                        }),
+                        Cell::from(s.2.last_activity.map(ago).unwrap_or_default()),
+                        Cell::from(error).style(Style::new().red()),
                     ])
                 });
 
-                let table = Table::new(rows, vec![30, 30, 20, 10])
+                let widths = [
+                    Constraint::Length(30),
+                    Constraint::Length(30),
+                    Constraint::Length(20),
+                    Constraint::Length(14),
+                    Constraint::Length(13),
+                    Constraint::Length(24),
+                    Constraint::Length(6),
+                    Constraint::Length(9),
+                    Constraint::Fill(1),
+                ];
+                let table = Table::new(rows, widths)
                     .block(block)
                     .header(
                         Row::new(vec![
                             Cell::from("Nickname"),
                             Cell::from("Identifier"),
                             Cell::from("Environment"),
+                            Cell::from("Account"),
+                            Cell::from("Port"),
                             Cell::from("Status"),
+                            Cell::from("Conns"),
+                            Cell::from("Activity"),
+                            Cell::from("Last Error"),
                         ])
                         .style(Style::new().bold().bg(Color::LightRed)),
                     )
@@ -233,7 +1043,57 @@ impl App {
 
                 f.render_stateful_widget(table, cunks[0], &mut self.table_state);
 
-                let help = Paragraph::new("up/down to move, e to edit, d to delete, s to save, a to add, space to start/stop").style(Style::new().bg(Color::Blue));
+                if let Mode::Login(profile) = &self.mode {
+                    let area = centered(cunks[0], 70, 6);
+                    let prompt = Paragraph::new(format!(
+                        "Credentials for profile {} have expired. Run `aws sso login --profile {}` and retry its sessions?",
+                        profile, profile
+                    ))
+                    .wrap(Wrap { trim: true })
+                    .block(
+                        Block::default()
+                            .title("Log in again?")
+                            .borders(Borders::ALL)
+                            .border_type(BorderType::Rounded),
+                    );
+                    f.render_widget(Clear, area);
+                    f.render_widget(prompt, area);
+                    let help = Paragraph::new("y to log in, n to leave it")
+                        .style(Style::new().bg(Color::Blue));
+                    f.render_widget(help, cunks[1]);
+                } else if let Some(notice) = &self.notice {
+                    let notice = Paragraph::new(notice.as_str()).style(Style::new().bg(Color::Red));
+                    f.render_widget(notice, cunks[1]);
+                } else {
+                    let help = Paragraph::new("up/down to move, e to edit, d to delete, s to save, a to add, space to start/stop, l to log in, i to import, r for remote sessions").style(Style::new().bg(Color::Blue));
+                    f.render_widget(help, cunks[1]);
+                }
+            }
+            Mode::Orphans(orphan_view) => {
+                orphan_view.draw(f, cunks[0], &self.server_list);
+                let help = Paragraph::new(
+                    "up/down to move, a to adopt into its server, k to kill, esc to leave the rest alone",
+                )
+                .style(Style::new().bg(Color::Blue));
+                f.render_widget(help, cunks[1]);
+            }
+            Mode::Import(import_view) => {
+                import_view.draw(f, cunks[0]);
+                let help = match import_view.listing {
+                    Listing::Profile => "type the profile to look in, return to list its instances, esc to cancel",
+                    Listing::Loading => "esc to cancel",
+                    Listing::Failed(_) => "return to try another profile, esc to cancel",
+                    Listing::Loaded(_) => "up/down to move, space to tick, return to add the ticked ones and save, esc to cancel",
+                };
+                let help = Paragraph::new(help).style(Style::new().bg(Color::Blue));
+                f.render_widget(help, cunks[1]);
+            }
+            Mode::Sessions(view) => {
+                view.draw(f, cunks[0]);
+                let help = Paragraph::new(
+                    "up/down to move, space to tick, t to terminate the ticked ones (or the highlighted one), r to refresh, esc to go back",
+                )
+                .style(Style::new().bg(Color::Blue));
                 f.render_widget(help, cunks[1]);
             }
             Mode::Edit(edit_view) => {
@@ -245,52 +1105,123 @@ impl App {
         }
     }
 
-    async fn poll_sessions(&mut self) {
-        let futs: Vec<_> = self
-            .server_list
-            .iter()
+    /// Waits for any session to publish a new state, returning its index.
+    async fn session_changed(server_list: &mut [Uhh]) -> usize {
+        if server_list.is_empty() {
+            return std::future::pending().await;
+        }
+        let futs = server_list
+            .iter_mut()
             .enumerate()
-            .map(|(i, (session, server, status))| {
-                let f = session.healthy();
-                async move { (i, f.await) }
-            })
-            .collect();
-        let res: Vec<(usize, bool)> = futures::future::join_all(futs).await;
-        for (i, status) in res {
-            self.server_list[i].2 = status;
+            .map(|(i, (session, _, _))| {
+                Box::pin(async move {
+                    match session.changed().await {
+                        Ok(()) => i,
+                        // The actor is gone, so this one will never change again.
+                        Err(_) => std::future::pending().await,
+                    }
+                })
+            });
+        futures::future::select_all(futs).await.0
+    }
+
+    /// When the next "ago" on screen goes stale, if there's one showing at all.
+    fn next_tick(&self) -> Option<Duration> {
+        if !matches!(self.mode, Mode::Main | Mode::Login(_)) {
+            return None;
         }
+        self.server_list
+            .iter()
+            .filter_map(|(_, _, state)| state.last_activity)
+            .map(ago_changes)
+            .min()
     }
 
     async fn handle_events(&mut self) -> Result<()> {
-        let mut interval = tokio::time::interval(Duration::from_millis(100));
-
+        let tick = match self.next_tick() {
+            Some(delay) => Either::Left(tokio::time::sleep(delay)),
+            None => Either::Right(std::future::pending()),
+        };
+        let event = match self.event_stream.as_mut() {
+            Some(stream) => Either::Left(stream.next()),
+            None => Either::Right(std::future::pending()),
+        };
         tokio::select! {
-            event = self.event_stream.next().fuse() => {
-                match event {
-                    Some(Ok(evt)) => {
-                        match evt {
-                            Event::Key(key)
-                                if key.kind == KeyEventKind::Press
-                                    => self.on_key_event(key).await,
-                            Event::Mouse(_) => {}
-                            Event::Resize(_, _) => {}
-                            _ => {}
+            event = event => {
+                if let Some(Ok(evt)) = event {
+                    match evt {
+                        Event::Key(key)
+                            if key.kind == KeyEventKind::Press
+                                => self.on_key_event(key).await,
+                        Event::Mouse(_) => {}
+                        Event::Resize(_, _) => {}
+                        _ => {}
+                    }
+                }
+            }
+            // Nothing to handle, but a time shown as "ago" needs redrawing.
+            _ = tick => {}
+            _ = &mut self.shutdown_signal => {
+                self.running = false;
+            }
+            Some((principal, identity)) = self.identity_rx.recv() => {
+                let profile = &principal.0;
+                let whoami = match identity {
+                    Ok(identity) => Whoami::Known(identity),
+                    Err(err) => {
+                        let error = err.to_string();
+                        // No point waiting for a session to trip over the same thing.
+                        let expired = Problem::classify(&error).is_some_and(|p| p.needs_login());
+                        if expired && matches!(self.mode, Mode::Main) && self.offered.insert(profile.clone()) {
+                            self.mode = Mode::Login(profile.clone());
                         }
+                        Whoami::Failed(error)
+                    }
+                };
+                self.identities.insert(principal, whoami);
+            }
+            Some((profile, instances)) = self.import_rx.recv() => {
+                if let Mode::Import(import_view) = &mut self.mode {
+                    // Only if it's still waiting on this profile, and not cancelled or moved on.
+                    if matches!(import_view.listing, Listing::Loading) && import_view.profile == profile {
+                        match instances {
+                            Ok(instances) => import_view.loaded(instances),
+                            Err(err) => import_view.listing = Listing::Failed(err.to_string()),
+                        }
+                    }
+                }
+            }
+            Some((target, sessions)) = self.sessions_rx.recv() => {
+                if let Mode::Sessions(view) = &mut self.mode {
+                    if view.sessions.is_none() && view.target == target {
+                        view.loaded(sessions);
                     }
-                    _ => {}
                 }
             }
-            _ = interval.tick() => {
+            i = Self::session_changed(&mut self.server_list) => {
+                let (session, server, state) = &mut self.server_list[i];
+                *state = session.state();
+                let expired = state.problem.as_ref().is_some_and(|p| p.needs_login());
+                if expired && matches!(self.mode, Mode::Main) && self.offered.insert(server.env.clone()) {
+                    self.mode = Mode::Login(server.env.clone());
+                }
                 if let Mode::Edit(edit_view) = &mut self.mode {
-                    edit_view.update().await;
+                    if edit_view.selected == i {
+                        edit_view.update().await;
+                    }
                 }
-                self.poll_sessions().await;
             }
         }
         Ok(())
     }
 
     async fn on_key_event(&mut self, key: KeyEvent) {
+        // Raw mode swallows SIGINT, so treat the key chord the same way from any mode.
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            self.running = false;
+            return;
+        }
+        self.notice = None;
         match &mut self.mode {
             Mode::Main => match key.code {
                 KeyCode::Esc | KeyCode::Char('q') => self.running = false,
@@ -299,7 +1230,8 @@ impl App {
                 KeyCode::Char('e') => {
                     if let Some(sel) = self.table_state.selected() {
                         let (session, server, _) = &self.server_list[sel];
-                        let mut edit_view = EditView::new(sel, session.clone(), server.clone());
+                        let mut edit_view =
+                            EditView::new(sel, session.clone(), server.clone(), &self.server_list);
                         edit_view.update().await;
                         self.mode = Mode::Edit(edit_view);
                     }
@@ -307,7 +1239,7 @@ impl App {
                 KeyCode::Char(' ') => {
                     if let Some(selected) = self.table_state.selected() {
                         let handle = self.server_list[selected].0.clone();
-                        let running = self.server_list[selected].2;
+                        let running = self.server_list[selected].2.phase.is_active();
                         tokio::spawn(async move {
                             if running {
                                 handle.stop().await;
@@ -317,34 +1249,63 @@ impl App {
                         });
                     }
                 }
-                KeyCode::Char('s') => {
-                    let servers: Vec<_> = self
-                        .server_list
-                        .iter()
-                        .map(|(_, server, _)| server.clone())
-                        .collect();
-                    let path = self.connections_file.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = crate::servers::save(path, &servers).await {
-                            eprintln!("Failed to save servers: {}", e);
-                        }
-                    });
-                }
+                KeyCode::Char('s') => self.save(),
                 KeyCode::Char('a') => {
                     let server = Server {
                         name: "A cool new server".into(),
                         identifier: "i-something".into(),
                         env: "a-profile".into(),
-                        host_port: 6969,
+                        // Auto, so shared configs stop colliding on hand picked ports.
+                        host_port: 0,
                         dest_port: 1337,
+                        ..Server::default()
                     };
-                    let session = Session::new(
-                        server.identifier.clone(),
-                        server.env.clone(),
-                        server.host_port,
-                        server.dest_port,
-                    );
-                    self.server_list.push((session, server, false));
+                    if let Err(e) = self.add(server).await {
+                        self.notice = Some(format!("Failed to add server: {}", e));
+                        return;
+                    }
+                    self.check_identities();
+                }
+                KeyCode::Char('r') => {
+                    let Some((_, server, state)) = self
+                        .table_state
+                        .selected()
+                        .and_then(|sel| self.server_list.get(sel))
+                        .filter(|(_, server, _)| server.backend.is_ssm())
+                    else {
+                        return;
+                    };
+                    let server = server.with_defaults(&self.defaults);
+                    let target = state.target.clone().unwrap_or(server.identifier.clone());
+                    let mut view =
+                        SessionsView::new(server.clone(), target.clone(), state.session_id.clone());
+                    if server.tag_selector().is_some() && state.target.is_none() {
+                        view.sessions = Some(Err(
+                            "The tag hasn't been resolved to an instance yet. Start it first."
+                                .to_string(),
+                        ));
+                    } else {
+                        view.list(&self.sessions_tx);
+                    }
+                    self.mode = Mode::Sessions(Box::new(view));
+                }
+                KeyCode::Char('i') => {
+                    let profile = self
+                        .table_state
+                        .selected()
+                        .and_then(|sel| self.server_list.get(sel))
+                        .map(|(_, server, _)| server.env.clone())
+                        .unwrap_or_default();
+                    self.mode = Mode::Import(ImportView::new(profile));
+                }
+                KeyCode::Char('l') => {
+                    if let Some((_, server, _)) = self
+                        .table_state
+                        .selected()
+                        .and_then(|sel| self.server_list.get(sel))
+                    {
+                        self.mode = Mode::Login(server.env.clone());
+                    }
                 }
                 KeyCode::Backspace | KeyCode::Char('d') => {
                     if let Some(sel) = self.table_state.selected() {
@@ -353,6 +1314,114 @@ impl App {
                 }
                 _ => {}
             },
+            Mode::Login(profile) => match key.code {
+                KeyCode::Char('y') | KeyCode::Enter => {
+                    self.login = Some(profile.clone());
+                    self.mode = Mode::Main;
+                }
+                KeyCode::Char('n') | KeyCode::Esc => self.mode = Mode::Main,
+                _ => {}
+            },
+            Mode::Import(import_view) => match (&import_view.listing, key.code) {
+                (_, KeyCode::Esc) => self.mode = Mode::Main,
+                (Listing::Profile, KeyCode::Char(c)) => import_view.profile.push(c),
+                (Listing::Profile, KeyCode::Backspace) => {
+                    import_view.profile.pop();
+                }
+                (Listing::Profile, KeyCode::Enter) if !import_view.profile.is_empty() => {
+                    let profile = import_view.profile.clone();
+                    import_view.existing = self
+                        .server_list
+                        .iter()
+                        .filter(|(_, server, _)| server.env == profile)
+                        .map(|(_, server, _)| server.identifier.clone())
+                        .collect();
+                    import_view.listing = Listing::Loading;
+                    self.list_instances(profile);
+                }
+                (Listing::Failed(_), KeyCode::Enter) => import_view.listing = Listing::Profile,
+                (Listing::Loaded(_), KeyCode::Up | KeyCode::Char('k')) => {
+                    import_view.table_state.select_previous()
+                }
+                (Listing::Loaded(_), KeyCode::Down | KeyCode::Char('j')) => {
+                    import_view.table_state.select_next()
+                }
+                (Listing::Loaded(_), KeyCode::Char(' ')) => import_view.toggle_selected(),
+                (Listing::Loaded(_), KeyCode::Enter) => {
+                    let servers = import_view.servers();
+                    self.mode = Mode::Main;
+                    if servers.is_empty() {
+                        return;
+                    }
+                    for server in servers {
+                        if let Err(e) = self.add(server).await {
+                            self.notice = Some(format!("Failed to add server: {}", e));
+                            return;
+                        }
+                    }
+                    self.check_identities();
+                    self.save();
+                }
+                _ => {}
+            },
+            Mode::Sessions(view) => match key.code {
+                KeyCode::Esc | KeyCode::Char('q') => self.mode = Mode::Main,
+                KeyCode::Up | KeyCode::Char('k') => view.table_state.select_previous(),
+                KeyCode::Down | KeyCode::Char('j') => view.table_state.select_next(),
+                KeyCode::Char(' ') => view.toggle_selected(),
+                KeyCode::Char('r') if view.sessions.is_some() => view.list(&self.sessions_tx),
+                KeyCode::Char('t') if matches!(view.sessions, Some(Ok(_))) => {
+                    let ids = view.to_terminate();
+                    if !ids.is_empty() {
+                        view.terminate(&self.sessions_tx, ids);
+                    }
+                }
+                _ => {}
+            },
+            Mode::Orphans(orphan_view) => {
+                match key.code {
+                    KeyCode::Up => orphan_view.table_state.select_previous(),
+                    KeyCode::Down => orphan_view.table_state.select_next(),
+                    KeyCode::Char('a') => {
+                        let adoptable = orphan_view
+                            .table_state
+                            .selected()
+                            .and_then(|sel| orphan_view.orphans.get(sel))
+                            .is_some_and(|o| o.server.is_some());
+                        if adoptable {
+                            if let Some(orphan) = orphan_view.take_selected() {
+                                if let Some((session, _, _)) = orphan
+                                    .server
+                                    .filter(|_| orphan.still_there())
+                                    .and_then(|i| self.server_list.get(i))
+                                {
+                                    session
+                                        .adopt(orphan.foreign(), orphan.record.host_port)
+                                        .await;
+                                }
+                            }
+                        }
+                    }
+                    KeyCode::Char('k') => {
+                        // Something else has the pid by now, and it isn't ours to kill.
+                        if let Some(orphan) =
+                            orphan_view.take_selected().filter(|o| o.still_there())
+                        {
+                            let foreign = orphan.foreign();
+                            tokio::spawn(async move {
+                                if foreign.stop().await.is_ok() {
+                                    Record::forget(foreign.pid);
+                                }
+                            });
+                        }
+                    }
+                    KeyCode::Esc | KeyCode::Enter => orphan_view.orphans.clear(),
+                    _ => {}
+                }
+                if orphan_view.orphans.is_empty() {
+                    self.mode = Mode::Main;
+                }
+            }
             Mode::Edit(edit_view) => {
                 if !edit_view.handle_key(key) {
                     match key.code {
@@ -366,19 +1435,20 @@ impl App {
                                 server.identifier = edit_view.form_fields[0].clone();
                                 server.name = edit_view.form_fields[1].clone();
                                 server.env = edit_view.form_fields[2].clone();
-                                server.host_port =
-                                    edit_view.form_fields[3].parse().unwrap_or(server.host_port);
+                                server.host_port = parse_port_field(&edit_view.form_fields[3])
+                                    .unwrap_or(server.host_port);
                                 server.dest_port =
                                     edit_view.form_fields[4].parse().unwrap_or(server.dest_port);
+                                let remote_host = edit_view.form_fields[5].trim();
+                                server.remote_host =
+                                    (!remote_host.is_empty()).then(|| remote_host.to_string());
 
                                 let session = session.clone();
-                                let identifer = server.identifier.clone();
-                                let env = server.env.clone();
-                                let host_port = server.host_port;
-                                let dest_port = server.dest_port;
+                                let server = server.clone();
                                 tokio::spawn(async move {
-                                    session.update(identifer, env, host_port, dest_port).await;
+                                    session.update(server).await;
                                 });
+                                self.check_identities();
                             }
                         }
                         KeyCode::Esc => {